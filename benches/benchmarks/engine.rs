@@ -8,8 +8,7 @@ use tempfile::TempDir;
 
 fn random_string(rng: &mut impl rand::RngCore) -> String {
     let size = rng.gen_range(1, 100000);
-    let rand_string = rng.sample_iter(&Alphanumeric).take(size).collect();
-    return rand_string;
+    rng.sample_iter(&Alphanumeric).take(size).collect()
 }
 
 fn kvs_write(c: &mut Criterion) {
@@ -60,10 +59,7 @@ fn kvs_read(c: &mut Criterion) {
     c.bench_function("kvs read", |b| {
         b.iter(|| {
             for key in &keys {
-                match store.get(key.to_owned()).unwrap() {
-                    None => assert!(false),
-                    _ => {}
-                }
+                assert!(store.get(key.to_owned()).unwrap().is_some());
             }
         });
     });
@@ -85,10 +81,7 @@ fn sled_read(c: &mut Criterion) {
     c.bench_function("sled read", |b| {
         b.iter(|| {
             for key in &keys {
-                match store.get(key.to_owned()).unwrap() {
-                    None => assert!(false),
-                    _ => {}
-                }
+                assert!(store.get(key.to_owned()).unwrap().is_some());
             }
         });
     });
@@ -1,3 +1,5 @@
+// `bench_function_over_inputs` is deprecated in favour of benchmark groups.
+#![allow(deprecated)]
 extern crate rand;
 extern crate rand_chacha;
 use criterion::{criterion_group, Criterion};
@@ -19,7 +21,7 @@ fn thread_pool_set(thread_pool: &impl ThreadPool, addr: SocketAddr) {
     for i in 0..1000 {
         let sender = mpsc::Sender::clone(&sender);
         thread_pool.spawn(move || {
-            let mut client = KvsClient::new(&addr).unwrap();
+            let mut client = KvsClient::new(addr).unwrap();
             client.set(format!("key{}", i), "value".to_owned()).unwrap();
             sender.send(()).unwrap();
         });
@@ -33,7 +35,7 @@ fn thread_pool_get(thread_pool: &impl ThreadPool, addr: SocketAddr) {
     for i in 0..1000 {
         let sender = mpsc::Sender::clone(&sender);
         thread_pool.spawn(move || {
-            let mut client = KvsClient::new(&addr).unwrap();
+            let mut client = KvsClient::new(addr).unwrap();
             let response = client.get(format!("key{}", i)).unwrap();
             assert_eq!(response, Some("value".to_string()));
             sender.send(()).unwrap();
@@ -0,0 +1,102 @@
+use crate::client::ClientOptions;
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::network::{read_message, write_message, MessageBuf, Request, Response};
+use crate::transport::{self, with_timeout, AsyncStream, KvsAddr};
+use std::time::Duration;
+use tokio_rustls::TlsConnector;
+
+/// An async client to speak to kvs server, built on tokio.
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::{AsyncKvsClient, Result};
+/// # use std::net::SocketAddr;
+/// #
+/// # async fn run() -> Result<()> {
+/// let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+/// let mut client = AsyncKvsClient::new(addr).await?;
+/// client.set("Key".to_owned(), "Value".to_owned()).await?;
+/// assert_eq!(client.get("Key".to_owned()).await?, Some("Value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    stream: AsyncStream,
+    buf: MessageBuf,
+    read_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+}
+
+impl AsyncKvsClient {
+    /// Create a connection to server.
+    ///
+    /// `addr` is either a TCP socket address or a Unix domain socket path, see [`KvsAddr`].
+    pub async fn new(addr: impl Into<KvsAddr>) -> Result<Self> {
+        AsyncKvsClient::connect(addr, &ClientOptions::default()).await
+    }
+
+    /// Create a connection to server with the given `options`.
+    pub async fn connect(addr: impl Into<KvsAddr>, options: &ClientOptions) -> Result<Self> {
+        let addr = addr.into();
+        let mut stream = transport::connect_async(&addr, options.connect_timeout).await?;
+        if let Some(ref tls) = options.tls {
+            let connector = TlsConnector::from(tls.build()?);
+            let handshake = connector.connect(tls.server_name(&addr)?, stream);
+            stream =
+                Box::new(with_timeout(options.connect_timeout, "TLS handshake", handshake).await?);
+        }
+        let mut client = AsyncKvsClient {
+            stream,
+            buf: MessageBuf::new(),
+            read_timeout: options.read_timeout,
+            write_timeout: options.write_timeout,
+        };
+        if let Some((ref user, ref token)) = options.credentials {
+            let request = Request::Auth {
+                user: user.to_owned(),
+                token: token.to_owned(),
+            };
+            client.send_and_recv(request).await?;
+        }
+        Ok(client)
+    }
+
+    /// Send to the server to insert a key/value, and wait for the server to respond.
+    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.send_and_recv(Request::Set { key, value }).await?;
+        Ok(())
+    }
+
+    /// Send to the server to get the value match the key, and wait for the server to respond.
+    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+        self.send_and_recv(Request::Get { key }).await
+    }
+
+    /// Send to the server to remove the given string key, and wait for the server to respond.
+    pub async fn remove(&mut self, key: String) -> Result<Option<String>> {
+        let request = Request::Remove { key: key.clone() };
+        match self.send_and_recv(request).await {
+            Err(KvsError::KeyNotFound) => Ok(None),
+            result => result.map(|_| Some(key)),
+        }
+    }
+
+    /// Send `request`, and turn an error response into the matching `KvsError`.
+    async fn send_and_recv(&mut self, request: Request) -> Result<Option<String>> {
+        write_message(&mut self.stream, &request, self.write_timeout).await?;
+        let response = read_message(
+            &mut self.stream,
+            &mut self.buf,
+            self.read_timeout,
+            self.read_timeout,
+        );
+        let response: Option<Response> = response.await?;
+        match response {
+            Some(response) => response.into_value(),
+            None => Err(KvsError::StringError(
+                "the server closed the connection".to_owned(),
+            )),
+        }
+    }
+}
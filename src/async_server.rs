@@ -0,0 +1,306 @@
+use crate::auth::Acl;
+use crate::engine::Result;
+use crate::limits::{is_timeout, Limits, LINGER, MAX_MESSAGE_SIZE};
+use crate::network::{read_message, write_message, MessageBuf, Request, Response};
+use crate::server::{process_cmd, Session};
+use crate::shutdown::{self, ShutdownHandle};
+use crate::slowlog::SlowLog;
+use crate::stats::Stats;
+use crate::tls::TlsServerConfig;
+use crate::transport::{AsyncIo, AsyncListener, KvsAddr};
+use crate::{KvsEngine, KvsError};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the server tries to tell a refused client it is busy.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A server to listen to the kvs client, built on tokio.
+///
+/// It speaks the same protocol as [`KvsServer`](crate::KvsServer), but each connection
+/// is a task rather than a thread pool job, so that idle connections cost little.
+/// Since engines are blocking, requests are run on tokio's blocking thread pool.
+///
+/// # Examples
+/// ```no_run
+/// # use tempfile::TempDir;
+/// # use kvs::{AsyncKvsServer, KvStore};
+/// # use std::net::SocketAddr;
+/// #
+/// # fn main() {
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = KvStore::open(temp_dir.path()).unwrap();
+/// let server = AsyncKvsServer::new(store);
+///
+/// let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+/// let runtime = tokio::runtime::Runtime::new().unwrap();
+/// runtime.block_on(server.run(addr)).unwrap();
+/// # }
+/// ```
+pub struct AsyncKvsServer<E: KvsEngine> {
+    store: E,
+    tls: Option<TlsServerConfig>,
+    acl: Option<Arc<Acl>>,
+    stats: Arc<Stats>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    limits: Limits,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    /// Create a server serving `engine`.
+    pub fn new(engine: E) -> Self {
+        AsyncKvsServer {
+            store: engine,
+            tls: None,
+            acl: None,
+            stats: Arc::new(Stats::default()),
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Encrypt the connections of kvs clients with TLS.
+    ///
+    /// The certificate and key files are loaded when the server runs.
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Keep the last `capacity` requests taking longer than `threshold` to run,
+    /// for clients to read with [`KvsClient::slowlog`](crate::KvsClient::slowlog).
+    /// Defaults to the last 128 requests over 10 milliseconds.
+    pub fn with_slowlog(mut self, threshold: Duration, capacity: usize) -> Self {
+        self.stats = Arc::new(Stats::new(SlowLog::new(threshold, capacity)));
+        self
+    }
+
+    /// Require clients to authenticate, and restrict what they can do with `acl`.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Wait at most `timeout` for the connections in flight when shutting down. Defaults to 30 seconds.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Close the connections that take longer than `timeout` to send the next bytes of a request.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Close the connections that do not accept a response within `timeout`.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Close the connections that send no request for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Refuse new clients while `max` of them are connected.
+    ///
+    /// Refused clients get a `Busy` error, except over TLS where the connection is just closed.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// A handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Listen on `addr` and handle the connections, until a shutdown is asked for.
+    ///
+    /// It must be run within a tokio runtime.
+    pub async fn run(self, addr: impl Into<KvsAddr>) -> Result<()> {
+        let listener = AsyncListener::bind(&addr.into()).await?;
+        let tls = match self.tls {
+            Some(ref tls) => Some(TlsAcceptor::from(tls.build()?)),
+            None => None,
+        };
+
+        // Every connection holds a sender, so that `recv` returns once they are all done.
+        let (running, mut done) = mpsc::channel::<()>(1);
+        let mut signal = self.shutdown.subscribe();
+        let slots = self
+            .limits
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown::requested(&mut signal) => break,
+            };
+            let (mut stream, peer) = match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!("accept error: {}", err);
+                    continue;
+                }
+            };
+
+            let slot = match slots
+                .as_ref()
+                .map(|slots| slots.clone().try_acquire_owned())
+            {
+                Some(Ok(slot)) => Some(slot),
+                Some(Err(_)) => {
+                    warn!("refusing connection from {}: too many connections", peer);
+                    if tls.is_none() {
+                        tokio::spawn(async move {
+                            let response: Response =
+                                KvsError::ServerBusy("Too many connections".to_owned()).into();
+                            let _ =
+                                write_message(&mut stream, &response, Some(REFUSE_TIMEOUT)).await;
+                        });
+                    }
+                    continue;
+                }
+                None => None,
+            };
+
+            info!("connection from {}", peer);
+            let tls = tls.clone();
+            let connection = Connection {
+                store: self.store.clone(),
+                signal: self.shutdown.subscribe(),
+                limits: self.limits,
+                _slot: slot,
+                _running: running.clone(),
+            };
+            let session = Session::new(self.acl.clone(), self.stats.clone(), peer.clone());
+            tokio::spawn(async move {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => connection.serve(stream, session).await,
+                        Err(err) => Err(err.into()),
+                    },
+                    None => connection.serve(stream, session).await,
+                };
+                if let Err(err) = result {
+                    error!("connection from {} failed: {}", peer, err);
+                }
+            });
+        }
+
+        info!("shutting down");
+        drop(running);
+        if tokio::time::timeout(self.shutdown_timeout, done.recv())
+            .await
+            .is_err()
+        {
+            warn!(
+                "connections still running after {:?}",
+                self.shutdown_timeout
+            );
+        }
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.flush())
+            .await
+            .map_err(|err| KvsError::ServerError(err.to_string()))?
+    }
+}
+
+/// What a client connection needs besides its stream and session.
+struct Connection<E: KvsEngine> {
+    store: E,
+    signal: watch::Receiver<bool>,
+    limits: Limits,
+    _slot: Option<OwnedSemaphorePermit>,
+    _running: mpsc::Sender<()>,
+}
+
+impl<E: KvsEngine> Connection<E> {
+    /// Answer the requests on `stream` until the client leaves, sends a malformed
+    /// request or times out, or a shutdown is asked for.
+    ///
+    /// A request being processed when the shutdown is asked for still gets its response.
+    async fn serve(mut self, mut stream: impl AsyncIo, mut session: Session) -> Result<()> {
+        let mut buf = MessageBuf::new().with_max_size(MAX_MESSAGE_SIZE);
+        let limits = self.limits;
+        loop {
+            let read = read_message(
+                &mut stream,
+                &mut buf,
+                limits.idle_timeout,
+                limits.read_timeout,
+            );
+            let request = tokio::select! {
+                request = read => request,
+                _ = shutdown::requested(&mut self.signal) => Ok(None),
+            };
+            let request = match request {
+                Ok(Some(request)) => request,
+                Ok(None) => return Ok(()),
+                Err(ref err) if is_timeout(err) => {
+                    warn!("closing connection from {}: {}", session.peer, err);
+                    return Ok(());
+                }
+                Err(err) => {
+                    return match session.reject(err) {
+                        Some(response) => {
+                            write_message(&mut stream, &response, limits.write_timeout).await?;
+                            linger(&mut stream).await;
+                            Ok(())
+                        }
+                        None => Ok(()),
+                    }
+                }
+            };
+            let (response, returned) = process(self.store.clone(), session, request).await?;
+            session = returned;
+            match write_message(&mut stream, &response, limits.write_timeout).await {
+                Err(ref err) if is_timeout(err) => {
+                    warn!("closing connection from {}: {}", session.peer, err);
+                    return Ok(());
+                }
+                result => result?,
+            }
+        }
+    }
+}
+
+/// Close the connection once the last response is sent, like `Stream::linger`.
+async fn linger(stream: &mut impl AsyncIo) {
+    if stream.shutdown().await.is_err() {
+        return;
+    }
+    let mut buf = [0; 4096];
+    let discard = async {
+        while let Ok(n) = stream.read(&mut buf).await {
+            if n == 0 {
+                return;
+            }
+        }
+    };
+    let _ = tokio::time::timeout(LINGER, discard).await;
+}
+
+/// Run `request` on the blocking thread pool, handing `session` back with the response.
+async fn process<E: KvsEngine>(
+    store: E,
+    mut session: Session,
+    request: Request,
+) -> Result<(Response, Session)> {
+    tokio::task::spawn_blocking(move || {
+        let response = process_cmd(&store, &mut session, request);
+        (response, session)
+    })
+    .await
+    .map_err(|err| KvsError::ServerError(err.to_string()))
+}
@@ -0,0 +1,90 @@
+use crate::engine::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// A right granted to a user on the keys sharing a prefix.
+///
+/// Each level includes the ones before it: `write` allows reading, and `admin`
+/// allows everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    /// Get keys.
+    Read,
+    /// Set and remove keys.
+    Write,
+    /// Run administrative commands.
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+struct Grant {
+    prefix: String,
+    access: Access,
+}
+
+#[derive(Debug, Deserialize)]
+struct User {
+    token: String,
+    #[serde(default)]
+    grants: Vec<Grant>,
+}
+
+/// The users allowed to connect to a [`KvsServer`](crate::KvsServer), and the rights
+/// they have on key prefixes.
+///
+/// An ACL is a JSON file mapping user names to their token and grants:
+///
+/// ```json
+/// {
+///     "users": {
+///         "alice": {
+///             "token": "s3cret",
+///             "grants": [
+///                 { "prefix": "", "access": "read" },
+///                 { "prefix": "alice/", "access": "write" }
+///             ]
+///         }
+///     }
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct Acl {
+    users: HashMap<String, User>,
+}
+
+impl Acl {
+    /// Load an ACL from the JSON file at `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Acl> {
+        let reader = BufReader::new(File::open(path)?);
+        let acl = serde_json::from_reader(reader)?;
+        Ok(acl)
+    }
+
+    /// Check that `token` is the token of `user`.
+    pub(crate) fn authenticate(&self, user: &str, token: &str) -> bool {
+        match self.users.get(user) {
+            Some(user) => constant_time_eq(user.token.as_bytes(), token.as_bytes()),
+            None => false,
+        }
+    }
+
+    /// Check that `user` has at least `access` on `key`.
+    pub(crate) fn is_allowed(&self, user: &str, key: &str, access: Access) -> bool {
+        match self.users.get(user) {
+            Some(user) => user
+                .grants
+                .iter()
+                .any(|grant| grant.access >= access && key.starts_with(&grant.prefix)),
+            None => false,
+        }
+    }
+}
+
+/// Compare secrets in a time independent of where they differ.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,9 +1,11 @@
-#[macro_use]
-extern crate failure;
+use kvs::parse_seconds;
+use kvs::KvsAddr;
 use kvs::KvsClient;
 use kvs::KvsError;
 use kvs::Result;
-use std::net::SocketAddr;
+use kvs::{ClientOptions, TlsClientConfig};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -12,23 +14,146 @@ pub enum Command {
     Set {
         key: String,
         value: String,
-        #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
     },
     #[structopt(name = "get", about = "Gets value according to the key")]
     Get {
         key: String,
-        #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
     },
     #[structopt(name = "rm", about = "Removes key/value pair according to the key")]
     Remove {
         key: String,
-        #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
-        addr: SocketAddr,
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(
+        name = "migrate",
+        about = "Moves the keys from start to end excluded to another server"
+    )]
+    Migrate {
+        start: String,
+        /// The first key not to move, all the keys from start are moved without it.
+        #[structopt(long = "end")]
+        end: Option<String>,
+        /// The server to move the keys to.
+        #[structopt(long = "target")]
+        target: KvsAddr,
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(name = "ping", about = "Checks the server is alive")]
+    Ping {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(
+        name = "info",
+        about = "Shows the engine of the server, its number of keys and size on disk"
+    )]
+    Info {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(
+        name = "stats",
+        about = "Shows the uptime of the server and its request counters"
+    )]
+    Stats {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(name = "compact", about = "Compacts the data of the server now")]
+    Compact {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(name = "flushall", about = "Removes every key of the server")]
+    FlushAll {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
+    },
+    #[structopt(
+        name = "slowlog",
+        about = "Shows the last requests the server was slow to run"
+    )]
+    SlowLog {
+        #[structopt(flatten)]
+        conn: ConnectionArgs,
     },
 }
 
+#[derive(Debug, StructOpt)]
+pub struct ConnectionArgs {
+    #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
+    addr: KvsAddr,
+
+    #[structopt(long = "tls-ca", parse(from_os_str))]
+    tls_ca: Option<PathBuf>,
+
+    #[structopt(long = "tls-domain", requires = "tls-ca")]
+    tls_domain: Option<String>,
+
+    #[structopt(long = "tls-cert", parse(from_os_str), requires_all = &["tls-ca", "tls-key"])]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(long = "user", requires = "token")]
+    user: Option<String>,
+
+    #[structopt(
+        long = "token",
+        requires = "user",
+        env = "KVS_TOKEN",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+
+    /// Seconds to wait for the server to accept the connection, a request or respond.
+    #[structopt(long = "timeout", parse(try_from_str = parse_seconds))]
+    timeout: Option<Duration>,
+
+    /// Follow redirects to the server to send the request to, e.g. the leader of a cluster.
+    #[structopt(long = "follow-redirects")]
+    follow_redirects: bool,
+}
+
+/// How many redirects a request follows with `--follow-redirects`.
+const MAX_REDIRECTS: u32 = 3;
+
+impl ConnectionArgs {
+    fn connect(&self) -> Result<KvsClient> {
+        let mut options = ClientOptions::default();
+        if let Some(ref ca) = self.tls_ca {
+            let mut tls = TlsClientConfig::new(ca);
+            if let Some(ref domain) = self.tls_domain {
+                tls = tls.with_domain(domain);
+            }
+            if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+                tls = tls.with_identity(cert, key);
+            }
+            options = options.with_tls(tls);
+        }
+        if let (Some(user), Some(token)) = (&self.user, &self.token) {
+            options = options.with_credentials(user, token);
+        }
+        if let Some(timeout) = self.timeout {
+            options = options
+                .with_connect_timeout(timeout)
+                .with_read_timeout(timeout)
+                .with_write_timeout(timeout);
+        }
+        if self.follow_redirects {
+            options = options.with_redirects(MAX_REDIRECTS);
+        }
+        KvsClient::connect(&self.addr, &options)
+    }
+}
+
 #[derive(Debug, StructOpt)]
 pub struct ApplicationArguments {
     #[structopt(subcommand)]
@@ -42,27 +167,59 @@ fn main() -> Result<()> {
         Command::Set {
             ref key,
             ref value,
-            ref addr,
+            ref conn,
         } => {
-            let mut client = KvsClient::new(addr)?;
+            let mut client = conn.connect()?;
             client.set(key.to_owned(), value.to_owned())?;
         }
-        Command::Get { ref key, ref addr } => {
-            let mut client = KvsClient::new(addr)?;
+        Command::Get { ref key, ref conn } => {
+            let mut client = conn.connect()?;
             let response = client.get(key.to_owned())?;
             match response {
                 Some(value) => println!("{}", value),
                 None => println!("Key not found"),
             }
         }
-        Command::Remove { ref key, ref addr } => {
-            let mut client = KvsClient::new(addr)?;
+        Command::Remove { ref key, ref conn } => {
+            let mut client = conn.connect()?;
             let response = client.remove(key.to_owned())?;
             if response.is_none() {
                 eprintln!("Key not found");
                 return Err(KvsError::KeyNotFound);
             }
         }
+        Command::Migrate {
+            ref start,
+            ref end,
+            ref target,
+            ref conn,
+        } => {
+            let mut client = conn.connect()?;
+            let copied = client.migrate(start.to_owned(), end.to_owned(), target.clone())?;
+            println!("{} keys moved to {}", copied, target);
+        }
+        Command::Ping { ref conn } => {
+            conn.connect()?.ping()?;
+            println!("PONG");
+        }
+        Command::Info { ref conn } => println!("{}", conn.connect()?.info()?),
+        Command::Stats { ref conn } => println!("{}", conn.connect()?.stats()?),
+        Command::Compact { ref conn } => conn.connect()?.compact()?,
+        Command::FlushAll { ref conn } => conn.connect()?.flush_all()?,
+        Command::SlowLog { ref conn } => {
+            for query in conn.connect()?.slowlog()? {
+                println!(
+                    "{} {} key={} value_size={} peer={} duration={:?}{}",
+                    query.timestamp,
+                    query.command,
+                    query.key.as_deref().unwrap_or("-"),
+                    query.value_size,
+                    query.peer,
+                    query.duration,
+                    if query.compacting { " compacting" } else { "" }
+                );
+            }
+        }
     }
     Ok(())
 }
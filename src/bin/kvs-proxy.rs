@@ -0,0 +1,92 @@
+#[macro_use]
+extern crate log;
+use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+use kvs::{parse_seconds, KvsAddr, Result};
+use kvs::{Acl, ClientOptions, KvsProxy, KvsServer, PoolOptions, ProxyOptions, ShutdownHandle};
+use log::LevelFilter;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Routes the requests of kvs clients to kvs servers by key")]
+pub struct ApplicationArguments {
+    #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
+    addr: KvsAddr,
+
+    /// The kvs servers to route the keys to.
+    #[structopt(long = "backends", use_delimiter = true, required = true)]
+    backends: Vec<KvsAddr>,
+
+    /// How many connections to keep open at most to each backend.
+    #[structopt(long = "pool-size", default_value = "8")]
+    pool_size: NonZeroUsize,
+
+    /// Seconds between health checks of the backends.
+    #[structopt(long = "health-interval", parse(try_from_str = parse_seconds))]
+    health_interval: Option<Duration>,
+
+    /// How many health checks or requests in a row a backend fails before being ejected.
+    #[structopt(long = "failure-limit")]
+    failure_limit: Option<NonZeroU32>,
+
+    /// Seconds to wait for a backend to accept a connection, a request or respond.
+    #[structopt(long = "backend-timeout", parse(try_from_str = parse_seconds))]
+    backend_timeout: Option<Duration>,
+
+    #[structopt(long = "acl", parse(from_os_str))]
+    acl: Option<PathBuf>,
+
+    #[structopt(long = "max-connections")]
+    max_connections: Option<usize>,
+}
+
+fn main() -> Result<()> {
+    simple_logging::log_to_stderr(LevelFilter::Info);
+    let opt = ApplicationArguments::from_args();
+
+    info!("proxy version: {}", env!("CARGO_PKG_VERSION"));
+    info!("Address: {}", opt.addr);
+    info!("Backends: {:?}", opt.backends);
+
+    let mut client = ClientOptions::default();
+    if let Some(timeout) = opt.backend_timeout {
+        client = client
+            .with_connect_timeout(timeout)
+            .with_read_timeout(timeout)
+            .with_write_timeout(timeout);
+    }
+    let mut options = ProxyOptions::default()
+        .with_pool(PoolOptions::default().with_max_size(opt.pool_size.get()));
+    if let Some(interval) = opt.health_interval {
+        options = options.with_health_check_interval(interval);
+    }
+    if let Some(limit) = opt.failure_limit {
+        options = options.with_failure_limit(limit.get());
+    }
+    let proxy = KvsProxy::new(opt.backends.clone(), client, options);
+
+    // Forwarding mostly waits on the backends, so run more requests than there are CPUs.
+    let pool = RayonThreadPool::new(4 * num_cpus::get() as u32)?;
+    let mut server = KvsServer::new(proxy, pool, None).with_event_loop();
+    if let Some(ref acl) = opt.acl {
+        server = server.with_acl(Acl::open(acl)?);
+    }
+    if let Some(max) = opt.max_connections {
+        server = server.with_max_connections(max);
+    }
+
+    stop_on_signal(server.shutdown_handle())?;
+    server.run(&opt.addr)
+}
+
+/// Shut the proxy down gracefully on SIGINT and SIGTERM.
+fn stop_on_signal(shutdown: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("received a termination signal");
+        shutdown.shutdown();
+    })
+    .map_err(|err| err.to_string())?;
+    Ok(())
+}
@@ -1,110 +1,701 @@
 #[macro_use]
-extern crate clap;
-#[macro_use]
 extern crate log;
-#[macro_use]
-extern crate failure;
-use kvs::thread_pool::{RayonThreadPool, ThreadPool};
-use kvs::KvsServer;
-use kvs::{KvStore, SledKvStore};
-use kvs::{KvsError, Result};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
+use kvs::TlsServerConfig;
+use kvs::{copy_engine, BoxedEngine, Checksum, EngineOptions, EngineRegistry};
+use kvs::{parse_seconds, DirLock, KvsAddr, KvsError, Result};
+use kvs::{Acl, AsyncKvsServer, ClientOptions, ClusterConfig, KvsServer, ShutdownHandle};
 use log::LevelFilter;
-use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
+use serde::Deserialize;
+use std::ffi::OsString;
+use std::fs::{self, OpenOptions};
 use std::net::SocketAddr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
 use structopt::StructOpt;
 
-arg_enum! {
-    #[allow(non_camel_case_types)]
-    #[derive(Debug, Serialize, Deserialize, PartialEq)]
-    enum Engine {
-        kvs,
-        sled
+/// How the server handles connections.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Mode {
+    /// A thread pool job per connection.
+    Threaded,
+    /// A tokio task per connection.
+    Async,
+    /// An event loop multiplexing the connections, handing requests to a thread pool.
+    Evented,
+}
+
+impl Mode {
+    const VARIANTS: &'static [&'static str] = &["threaded", "async", "evented"];
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "threaded" => Ok(Mode::Threaded),
+            "async" => Ok(Mode::Async),
+            "evented" => Ok(Mode::Evented),
+            _ => Err(format!("invalid mode: {}", s)),
+        }
+    }
+}
+
+/// The thread pool running the connections of the threaded and evented modes.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum Pool {
+    Rayon,
+    SharedQueue,
+    Naive,
+}
+
+impl Pool {
+    const VARIANTS: &'static [&'static str] = &["rayon", "shared-queue", "naive"];
+}
+
+impl FromStr for Pool {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rayon" => Ok(Pool::Rayon),
+            "shared-queue" => Ok(Pool::SharedQueue),
+            "naive" => Ok(Pool::Naive),
+            _ => Err(format!("invalid thread pool: {}", s)),
+        }
     }
 }
 
+/// When the writes of the kvs engine reach the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Durability {
+    /// Hand every write to the OS, which writes it to the disk later.
+    Flush,
+    /// Wait for every write to be on the disk.
+    Sync,
+}
+
+impl Durability {
+    const VARIANTS: &'static [&'static str] = &["flush", "sync"];
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(format!("invalid durability: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Copies the data of the data directory into a fresh directory with another
+    /// engine, checking the copy. The server must be stopped.
+    #[structopt(name = "migrate")]
+    Migrate {
+        /// The fresh directory to copy the data to.
+        #[structopt(long = "to", parse(from_os_str))]
+        to: PathBuf,
+
+        /// The engine to copy the data to.
+        #[structopt(long = "engine", possible_values = engine_names())]
+        engine: String,
+    },
+}
+
+/// The settings are taken from the flags, then from the `KVS_*` environment
+/// variables, then from the `--config` file.
 #[derive(Debug, StructOpt)]
 pub struct ApplicationArguments {
-    #[structopt(long = "addr", default_value = "127.0.0.1:4000")]
-    addr: SocketAddr,
+    /// A TOML file holding the settings not given by flags or environment variables.
+    #[structopt(long = "config", env = "KVS_CONFIG", parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    /// The address to listen to [default: 127.0.0.1:4000]
+    #[structopt(long = "addr", env = "KVS_ADDR")]
+    addr: Option<KvsAddr>,
+
+    /// The directory holding the data [default: ./]
+    #[structopt(long = "data-dir", env = "KVS_DATA_DIR", parse(from_os_str))]
+    data_dir: Option<PathBuf>,
+
+    /// The engine to store the data with [default: the engine the data was written with, or kvs]
+    #[structopt(long = "engine", env = "KVS_ENGINE", possible_values = engine_names())]
+    engine: Option<String>,
+
+    /// How connections are handled [default: threaded]
+    #[structopt(long = "mode", env = "KVS_MODE", possible_values = Mode::VARIANTS)]
+    mode: Option<Mode>,
+
+    /// The thread pool of the threaded and evented modes [default: rayon]
+    #[structopt(long = "pool", env = "KVS_POOL", possible_values = Pool::VARIANTS)]
+    pool: Option<Pool>,
+
+    /// The number of threads of the pool [default: the number of CPUs]
+    #[structopt(long = "pool-size", env = "KVS_POOL_SIZE")]
+    pool_size: Option<u32>,
+
+    /// Whether the kvs engine syncs every write to the disk [default: flush]
+    #[structopt(long = "durability", env = "KVS_DURABILITY", possible_values = Durability::VARIANTS)]
+    durability: Option<Durability>,
+
+    /// The number of stale entries in the log of the kvs engine triggering a compaction.
+    #[structopt(long = "compaction-threshold", env = "KVS_COMPACTION_THRESHOLD")]
+    compaction_threshold: Option<u64>,
+
+    #[structopt(long = "http-addr")]
+    http_addr: Option<SocketAddr>,
 
-    #[structopt(long = "engine", possible_values = &Engine::variants())]
-    engine: Option<Engine>,
+    /// Serve the metrics of the server on `GET /metrics` at this address, for Prometheus.
+    #[structopt(long = "metrics-addr")]
+    metrics_addr: Option<SocketAddr>,
+
+    #[structopt(long = "tls-cert", parse(from_os_str), requires = "tls-key")]
+    tls_cert: Option<PathBuf>,
+
+    #[structopt(long = "tls-key", parse(from_os_str), requires = "tls-cert")]
+    tls_key: Option<PathBuf>,
+
+    #[structopt(long = "tls-client-ca", parse(from_os_str), requires = "tls-cert")]
+    tls_client_ca: Option<PathBuf>,
+
+    #[structopt(long = "acl", parse(from_os_str))]
+    acl: Option<PathBuf>,
+
+    /// Seconds to wait for the next bytes of a request being received.
+    #[structopt(long = "read-timeout", env = "KVS_READ_TIMEOUT", parse(try_from_str = parse_seconds))]
+    read_timeout: Option<Duration>,
+
+    /// Seconds to wait for a client to accept the bytes of a response.
+    #[structopt(long = "write-timeout", env = "KVS_WRITE_TIMEOUT", parse(try_from_str = parse_seconds))]
+    write_timeout: Option<Duration>,
+
+    /// Seconds to wait for the next request of a client.
+    #[structopt(long = "idle-timeout", env = "KVS_IDLE_TIMEOUT", parse(try_from_str = parse_seconds))]
+    idle_timeout: Option<Duration>,
+
+    #[structopt(long = "max-connections")]
+    max_connections: Option<usize>,
+
+    /// Seconds over which a request is recorded in the slow-query log.
+    #[structopt(
+        long = "slowlog-threshold",
+        default_value = "0.01",
+        parse(try_from_str = parse_seconds)
+    )]
+    slowlog_threshold: Duration,
+
+    /// How many slow requests to keep, 0 disabling the slow-query log.
+    #[structopt(long = "slowlog-size", default_value = "128")]
+    slowlog_size: usize,
+
+    /// The user to authenticate as to the primary or the other nodes of the cluster.
+    #[structopt(long = "user", requires = "token")]
+    user: Option<String>,
+
+    #[structopt(
+        long = "token",
+        requires = "user",
+        env = "KVS_TOKEN",
+        hide_env_values = true
+    )]
+    token: Option<String>,
+
+    /// Replicate the server at this address, serving reads and redirecting writes to it.
+    #[structopt(long = "replica-of")]
+    replica_of: Option<KvsAddr>,
+
+    /// Run as a node of a Raft cluster of these addresses, `--addr` being one of them.
+    #[structopt(long = "cluster", use_delimiter = true, conflicts_with = "replica-of")]
+    cluster: Option<Vec<KvsAddr>>,
+
+    /// The least severe messages logged: off, error, warn, info, debug or trace [default: info]
+    #[structopt(long = "log-level", env = "KVS_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+
+    /// Log to this file instead of stderr.
+    #[structopt(long = "log-file", env = "KVS_LOG_FILE", parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+/// The settings of a `--config` file, e.g.
+///
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// data_dir = "/var/lib/kvs"
+/// engine = "kvs"
+/// mode = "threaded"
+///
+/// [pool]
+/// kind = "rayon"
+/// size = 8
+///
+/// [storage]
+/// durability = "sync"
+/// compaction_threshold = 1000
+///
+/// [timeouts]
+/// read = 5
+/// write = 5
+/// idle = 60
+///
+/// [log]
+/// level = "info"
+/// file = "/var/log/kvs.log"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    addr: Option<String>,
+    data_dir: Option<PathBuf>,
+    engine: Option<String>,
+    mode: Option<Mode>,
+    pool: PoolSection,
+    storage: StorageSection,
+    timeouts: TimeoutsSection,
+    log: LogSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PoolSection {
+    kind: Option<Pool>,
+    size: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StorageSection {
+    durability: Option<Durability>,
+    compaction_threshold: Option<u64>,
+}
+
+/// In seconds.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TimeoutsSection {
+    read: Option<f64>,
+    write: Option<f64>,
+    idle: Option<f64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    level: Option<String>,
+    file: Option<PathBuf>,
+}
+
+impl ApplicationArguments {
+    /// Fill the settings not given by flags or environment variables from the `--config` file.
+    fn load_config(&mut self) -> Result<()> {
+        let path = match self.config {
+            Some(ref path) => path.clone(),
+            None => return Ok(()),
+        };
+        let invalid = |err: String| KvsError::StringError(format!("{}: {}", path.display(), err));
+        let seconds = |secs: Option<f64>| {
+            secs.map(|secs| parse_seconds(&secs.to_string()).map_err(invalid))
+                .transpose()
+        };
+        let file: ConfigFile =
+            toml::from_str(&fs::read_to_string(&path)?).map_err(|err| invalid(err.to_string()))?;
+
+        if self.addr.is_none() {
+            self.addr = file.addr.map(|addr| addr.parse()).transpose()?;
+        }
+        if self.log_level.is_none() {
+            self.log_level = file
+                .log
+                .level
+                .map(|level| {
+                    level
+                        .parse()
+                        .map_err(|_| invalid(format!("invalid log level: {}", level)))
+                })
+                .transpose()?;
+        }
+        if self.read_timeout.is_none() {
+            self.read_timeout = seconds(file.timeouts.read)?;
+        }
+        if self.write_timeout.is_none() {
+            self.write_timeout = seconds(file.timeouts.write)?;
+        }
+        if self.idle_timeout.is_none() {
+            self.idle_timeout = seconds(file.timeouts.idle)?;
+        }
+        self.data_dir = self.data_dir.take().or(file.data_dir);
+        self.engine = self.engine.take().or(file.engine);
+        self.mode = self.mode.or(file.mode);
+        self.pool = self.pool.or(file.pool.kind);
+        self.pool_size = self.pool_size.or(file.pool.size);
+        self.durability = self.durability.or(file.storage.durability);
+        self.compaction_threshold = self
+            .compaction_threshold
+            .or(file.storage.compaction_threshold);
+        self.log_file = self.log_file.take().or(file.log.file);
+        Ok(())
+    }
+
+    fn addr(&self) -> KvsAddr {
+        self.addr
+            .clone()
+            .unwrap_or_else(|| KvsAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 4000))))
+    }
+
+    fn data_dir(&self) -> &Path {
+        self.data_dir.as_deref().unwrap_or_else(|| Path::new("./"))
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode.unwrap_or(Mode::Threaded)
+    }
+
+    /// How to connect to the primary or the other nodes of the cluster.
+    fn client_options(&self) -> ClientOptions {
+        match (&self.user, &self.token) {
+            (Some(user), Some(token)) => ClientOptions::default().with_credentials(user, token),
+            _ => ClientOptions::default(),
+        }
+    }
+
+    fn engine_options(&self) -> EngineOptions {
+        let mut options =
+            EngineOptions::default().with_sync_writes(self.durability == Some(Durability::Sync));
+        if let Some(threshold) = self.compaction_threshold {
+            options = options.with_compaction_threshold(threshold);
+        }
+        options
+    }
 }
 
 fn main() -> Result<()> {
-    simple_logging::log_to_stderr(LevelFilter::Info);
-    let opt = ApplicationArguments::from_args();
+    let mut opt = ApplicationArguments::from_args();
+    opt.load_config()?;
+    let level = opt.log_level.unwrap_or(LevelFilter::Info);
+    match opt.log_file {
+        Some(ref path) => simple_logging::log_to_file(path, level)?,
+        None => simple_logging::log_to_stderr(level),
+    }
+
+    let registry = EngineRegistry::default();
+    if let Some(Command::Migrate { ref to, ref engine }) = opt.command {
+        return migrate(&registry, &opt, to, engine);
+    }
 
     info!("server version: {}", env!("CARGO_PKG_VERSION"));
-    info!("IP:PORT {:?}", opt.addr);
+    info!("Address: {}", opt.addr());
+    info!("Data directory: {}", opt.data_dir().display());
     info!("Engine: {:?}", opt.engine);
+    info!("Mode: {:?}", opt.mode());
+    if let Some(http_addr) = opt.http_addr {
+        info!("HTTP IP:PORT {:?}", http_addr);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        info!("Metrics IP:PORT {:?}", metrics_addr);
+    }
+    if let Some(ref primary) = opt.replica_of {
+        info!("Replica of: {}", primary);
+    }
+    if let Some(ref nodes) = opt.cluster {
+        info!("Cluster: {:?}", nodes);
+    }
 
-    let ncpu = num_cpus::get();
-    let ncpu = ncpu as u32;
-    let pool = RayonThreadPool::new(ncpu)?;
+    let dir = opt.data_dir();
+    fs::create_dir_all(dir)?;
+    // Held until the server exits, keeping a second server off the directory.
+    let _lock = DirLock::acquire(dir, "kvs-server.lock")?;
+    let engine = get_engine(&registry, opt.engine.clone(), dir)?;
+    let store = registry.open(&engine, dir, &opt.engine_options())?;
+    run_server(store, &opt)
+}
 
-    let engine = get_engine(opt.engine)?;
-    match engine {
-        Engine::kvs => {
-            let store = KvStore::open("./")?;
-            let server = KvsServer::new(store, pool, None);
-            server.run(opt.addr)?;
-        }
-        Engine::sled => {
-            let store = SledKvStore::open("./")?;
-            let server = KvsServer::new(store, pool, None);
-            server.run(opt.addr)?;
+fn run_server(store: BoxedEngine, opt: &ApplicationArguments) -> Result<()> {
+    if opt.mode() == Mode::Async {
+        return run_async(store, opt);
+    }
+    let threads = opt.pool_size.unwrap_or(num_cpus::get() as u32);
+    let pool = opt.pool.unwrap_or(Pool::Rayon);
+    info!("Pool: {:?} of {} threads", pool, threads);
+    match pool {
+        Pool::Rayon => run_pooled(store, RayonThreadPool::new(threads)?, opt),
+        Pool::SharedQueue => run_pooled(store, SharedQueueThreadPool::new(threads)?, opt),
+        Pool::Naive => run_pooled(store, NaiveThreadPool::new(threads)?, opt),
+    }
+}
+
+fn run_pooled<P>(store: BoxedEngine, pool: P, opt: &ApplicationArguments) -> Result<()>
+where
+    P: ThreadPool + Send + Sync + 'static,
+{
+    let mut server = KvsServer::new(store, pool, None);
+    if opt.mode() == Mode::Evented {
+        server = server.with_event_loop();
+    }
+    if let Some(http_addr) = opt.http_addr {
+        server = server.with_http_addr(http_addr);
+    }
+    if let Some(metrics_addr) = opt.metrics_addr {
+        server = server.with_metrics_addr(metrics_addr);
+    }
+    if let Some(tls) = tls_config(opt) {
+        server = server.with_tls(tls);
+    }
+    if let Some(ref acl) = opt.acl {
+        server = server.with_acl(Acl::open(acl)?);
+    }
+    if let Some(timeout) = opt.read_timeout {
+        server = server.with_read_timeout(timeout);
+    }
+    if let Some(timeout) = opt.write_timeout {
+        server = server.with_write_timeout(timeout);
+    }
+    if let Some(timeout) = opt.idle_timeout {
+        server = server.with_idle_timeout(timeout);
+    }
+    if let Some(max) = opt.max_connections {
+        server = server.with_max_connections(max);
+    }
+    server = server.with_slowlog(opt.slowlog_threshold, opt.slowlog_size);
+    server = server.with_migration_file(opt.data_dir().join("migrations.json"));
+    if let Some(ref primary) = opt.replica_of {
+        server = server.with_replica_of(primary, opt.client_options());
+    }
+    let addr = opt.addr();
+    if let Some(ref nodes) = opt.cluster {
+        let id = nodes
+            .iter()
+            .position(|node| *node == addr)
+            .ok_or_else(|| KvsError::StringError(format!("{} is not part of the cluster", addr)))?;
+        let dir = opt.data_dir().join("raft");
+        let cluster = ClusterConfig::new(nodes.clone(), id, dir);
+        server = server.with_cluster(cluster.with_client_options(opt.client_options()));
+    }
+
+    stop_on_signal(server.shutdown_handle())?;
+    server.run(&addr)
+}
+
+fn run_async(store: BoxedEngine, opt: &ApplicationArguments) -> Result<()> {
+    if opt.http_addr.is_some() {
+        return Err(KvsError::StringError(
+            "the HTTP gateway is only available in threaded mode".to_owned(),
+        ));
+    }
+    if opt.metrics_addr.is_some() {
+        return Err(KvsError::StringError(
+            "metrics are only available in threaded mode".to_owned(),
+        ));
+    }
+    if opt.replica_of.is_some() || opt.cluster.is_some() {
+        return Err(KvsError::StringError(
+            "replication is not available in async mode".to_owned(),
+        ));
+    }
+
+    let mut server = AsyncKvsServer::new(store);
+    if let Some(tls) = tls_config(opt) {
+        server = server.with_tls(tls);
+    }
+    if let Some(ref acl) = opt.acl {
+        server = server.with_acl(Acl::open(acl)?);
+    }
+    if let Some(timeout) = opt.read_timeout {
+        server = server.with_read_timeout(timeout);
+    }
+    if let Some(timeout) = opt.write_timeout {
+        server = server.with_write_timeout(timeout);
+    }
+    if let Some(timeout) = opt.idle_timeout {
+        server = server.with_idle_timeout(timeout);
+    }
+    if let Some(max) = opt.max_connections {
+        server = server.with_max_connections(max);
+    }
+    server = server.with_slowlog(opt.slowlog_threshold, opt.slowlog_size);
+
+    stop_on_signal(server.shutdown_handle())?;
+    let runtime = tokio::runtime::Runtime::new()?;
+    runtime.block_on(server.run(&opt.addr()))
+}
+
+fn tls_config(opt: &ApplicationArguments) -> Option<TlsServerConfig> {
+    let (cert, key) = (opt.tls_cert.as_ref()?, opt.tls_key.as_ref()?);
+    let mut tls = TlsServerConfig::new(cert, key);
+    if let Some(ref ca) = opt.tls_client_ca {
+        tls = tls.with_client_ca(ca);
+    }
+    Some(tls)
+}
+
+/// Shut the server down gracefully on SIGINT and SIGTERM.
+fn stop_on_signal(shutdown: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || {
+        info!("received a termination signal");
+        shutdown.shutdown();
+    })
+    .map_err(|err| err.to_string())?;
+    Ok(())
+}
+
+/// Copy the data of the data directory to the fresh directory `to` with `to_engine`.
+fn migrate(
+    registry: &EngineRegistry,
+    opt: &ApplicationArguments,
+    to: &Path,
+    to_engine: &str,
+) -> Result<()> {
+    let from = opt.data_dir();
+    if !from.join("config").exists() {
+        return Err(KvsError::StringError(format!(
+            "{} holds no data to migrate",
+            from.display()
+        )));
+    }
+    check_engine(registry, to_engine)?;
+    if to.exists() && fs::read_dir(to)?.next().is_some() {
+        return Err(KvsError::StringError(format!(
+            "{} is not empty",
+            to.display()
+        )));
+    }
+    let _from_lock = DirLock::acquire(from, "kvs-server.lock")?;
+    let from_engine = get_engine(registry, opt.engine.clone(), from)?;
+    info!(
+        "Migrating {} in {} to {} in {}",
+        from_engine,
+        from.display(),
+        to_engine,
+        to.display()
+    );
+
+    // The copy is made next to `to` and renamed onto it once checked, so that
+    // `to` never holds a partial copy.
+    let copy = copy_dir(to)?;
+    if let Err(err) = fs::create_dir(&copy) {
+        return Err(KvsError::StringError(format!(
+            "cannot create {}: {}, it may be left by an interrupted migration",
+            copy.display(),
+            err
+        )));
+    }
+    let checksum = match copy_data(registry, opt, &from_engine, &copy, to_engine) {
+        Ok(checksum) => checksum,
+        Err(err) => {
+            if let Err(err) = fs::remove_dir_all(&copy) {
+                warn!("failed to remove {}: {}", copy.display(), err);
+            }
+            return Err(err);
         }
+    };
+    if to.exists() {
+        fs::remove_dir(to)?;
     }
+    fs::rename(&copy, to)?;
+    info!("Migrated {}", checksum);
+    println!("{}", checksum);
     Ok(())
 }
 
-fn get_engine(possible_engine: Option<Engine>) -> Result<Engine> {
-    let mut persisted_engine: Option<Engine> = None;
-    if Path::new("config").exists() {
-        let f = OpenOptions::new().read(true).open("config")?;
+/// The directory next to `to` the data is copied to before it is renamed onto `to`.
+fn copy_dir(to: &Path) -> Result<PathBuf> {
+    match to.file_name() {
+        Some(name) => {
+            let mut copy_name = OsString::from(".");
+            copy_name.push(name);
+            copy_name.push(".migrating");
+            Ok(to.with_file_name(copy_name))
+        }
+        None => Err(KvsError::StringError(format!(
+            "{} is not a directory name",
+            to.display()
+        ))),
+    }
+}
+
+/// Copy the data of the data directory to the fresh directory `copy` with `to_engine`.
+fn copy_data(
+    registry: &EngineRegistry,
+    opt: &ApplicationArguments,
+    from_engine: &str,
+    copy: &Path,
+    to_engine: &str,
+) -> Result<Checksum> {
+    let _lock = DirLock::acquire(copy, "kvs-server.lock")?;
+    let options = opt.engine_options();
+    let source = registry.open(from_engine, opt.data_dir(), &options)?;
+    let target = registry.open(to_engine, copy, &options)?;
+    let checksum = copy_engine(&source, &target)?;
+    get_engine(registry, Some(to_engine.to_owned()), copy)?;
+    Ok(checksum)
+}
+
+/// The engine to open `dir` with, checking it is registered and is the one the
+/// data was written with, which is persisted in `dir`.
+fn get_engine(
+    registry: &EngineRegistry,
+    possible_engine: Option<String>,
+    dir: &Path,
+) -> Result<String> {
+    let marker = dir.join("config");
+    let mut persisted_engine: Option<String> = None;
+    if marker.exists() {
+        let f = OpenOptions::new().read(true).open(&marker)?;
         let engine = serde_json::from_reader(f)?;
         persisted_engine = Some(engine);
     }
 
-    let engine: Engine;
-    match possible_engine {
-        Some(v) => match persisted_engine {
-            Some(p) => {
-                if v != p {
-                    return Err(KvsError::MismatchEngine);
-                }
-                engine = v;
-            }
-            None => engine = v,
-        },
-        None => match persisted_engine {
-            Some(v) => engine = v,
-            None => engine = Engine::kvs,
-        },
-    }
+    let engine = match (possible_engine, persisted_engine) {
+        (Some(v), Some(p)) if v != p => return Err(KvsError::MismatchEngine),
+        (Some(v), _) | (None, Some(v)) => v,
+        (None, None) => "kvs".to_owned(),
+    };
+    check_engine(registry, &engine)?;
 
     let f = OpenOptions::new()
         .write(true)
         .read(true)
         .truncate(true)
         .create(true)
-        .open("config")?;
-
-    match engine {
-        Engine::kvs => {
-            // let kv_store = KvStore::open("./")?;
-            serde_json::to_writer(f, &engine)?;
-            Ok(Engine::kvs)
-            // Ok(Box::new(kv_store))
-        }
-        Engine::sled => {
-            // let kv_store = SledKvStore::open("./")?;
-            serde_json::to_writer(f, &engine)?;
-            Ok(Engine::sled)
-            // Ok(Box::new(kv_store))
-        }
+        .open(&marker)?;
+    serde_json::to_writer(f, &engine)?;
+    Ok(engine)
+}
+
+/// Check that `engine` is registered.
+fn check_engine(registry: &EngineRegistry, engine: &str) -> Result<()> {
+    if !registry.names().any(|name| name == engine) {
+        let names: Vec<&str> = registry.names().collect();
+        return Err(KvsError::StringError(format!(
+            "unknown engine {}, expected one of {}",
+            engine,
+            names.join(", ")
+        )));
     }
+    Ok(())
+}
+
+/// The engines `--engine` accepts, the ones of the default registry.
+fn engine_names() -> &'static [&'static str] {
+    // Computed once for all the subcommands, so the names are only leaked once.
+    static NAMES: OnceLock<Vec<&'static str>> = OnceLock::new();
+    NAMES.get_or_init(|| {
+        EngineRegistry::default()
+            .names()
+            .map(|name| &*Box::leak(name.to_owned().into_boxed_str()))
+            .collect()
+    })
 }
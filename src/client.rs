@@ -1,8 +1,15 @@
 use crate::engine::Result;
-use crate::network::{Request, Response};
+use crate::error::KvsError;
+use crate::network::{ErrorCode, Request, Response};
+use crate::replication::{Feed, PRIMARY_TIMEOUT};
+use crate::retry::RetryPolicy;
+use crate::slowlog::SlowQuery;
+use crate::tls::TlsClientConfig;
+use crate::transport::{KvsAddr, Stream};
 use serde::Deserialize;
-use std::io::Write;
-use std::net::{SocketAddr, TcpStream};
+use std::io::{self, BufReader, Write};
+use std::thread;
+use std::time::Duration;
 
 /// A client to speak to kvs server.
 ///
@@ -32,50 +39,315 @@ use std::net::{SocketAddr, TcpStream};
 /// # }
 /// ```
 pub struct KvsClient {
-    stream: TcpStream,
+    stream: Stream,
+    addr: KvsAddr,
+    options: ClientOptions,
+}
+
+/// Options of the connection a [`KvsClient`] makes to the server.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    pub(crate) tls: Option<TlsClientConfig>,
+    pub(crate) credentials: Option<(String, String)>,
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) retry: Option<RetryPolicy>,
+    pub(crate) max_redirects: u32,
+}
+
+impl ClientOptions {
+    /// Authenticate as `user` with `token` once connected.
+    pub fn with_credentials(mut self, user: impl Into<String>, token: impl Into<String>) -> Self {
+        self.credentials = Some((user.into(), token.into()));
+        self
+    }
+
+    /// Encrypt the connection with TLS.
+    pub fn with_tls(mut self, tls: TlsClientConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Give up connecting to a TCP address after `timeout`.
+    pub fn with_connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up waiting for a response after `timeout` without receiving anything.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Give up sending a request after `timeout` without the server accepting anything.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Retry the requests that fail because of the connection or a busy server,
+    /// see [`RetryPolicy`]. By default requests are not retried.
+    pub fn with_retry(mut self, retry: RetryPolicy) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// Follow at most `max` redirects per request, reconnecting to the server the
+    /// request is redirected to, e.g. the leader of a cluster.
+    ///
+    /// By default redirects are not followed, and come back as a
+    /// [`Redirect`](KvsError::Redirect) error.
+    pub fn with_redirects(mut self, max: u32) -> Self {
+        self.max_redirects = max;
+        self
+    }
 }
 
 impl KvsClient {
     /// Create a connection to server.
-    pub fn new(addr: &SocketAddr) -> Result<Self> {
-        let stream = TcpStream::connect(addr)?;
-        Ok(KvsClient { stream })
+    ///
+    /// `addr` is either a TCP socket address or a Unix domain socket path, see [`KvsAddr`].
+    pub fn new(addr: impl Into<KvsAddr>) -> Result<Self> {
+        KvsClient::connect(addr, &ClientOptions::default())
+    }
+
+    /// Create a connection to server with the given `options`.
+    pub fn connect(addr: impl Into<KvsAddr>, options: &ClientOptions) -> Result<Self> {
+        let addr = addr.into();
+        let mut attempt = 1;
+        loop {
+            match open(&addr, options) {
+                Ok(stream) => {
+                    return Ok(KvsClient {
+                        stream,
+                        addr,
+                        options: options.clone(),
+                    })
+                }
+                Err(err) => attempt = backoff(options, attempt, None, err)?,
+            }
+        }
     }
 
     /// Send to the server to insert a key/value, and wait for the server to respond.
     pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let request = Request::Set { key, value };
-        send_and_recv(&mut self.stream, request)?;
+        self.request(Request::Set { key, value })?.into_value()?;
         Ok(())
     }
 
     /// Send to the server to get the value match the key, and wait for the server to respond.
     pub fn get(&mut self, key: String) -> Result<Option<String>> {
-        let request = Request::Get { key };
-        let response = send_and_recv(&mut self.stream, request)?;
-        if let Response::Ok(option) = response {
-            return Ok(option);
-        }
-        Ok(None)
+        self.request(Request::Get { key })?.into_value()
     }
 
     /// Send to the server to remove the given string key, and wait for the server to respond.
     pub fn remove(&mut self, key: String) -> Result<Option<String>> {
         let request = Request::Remove { key: key.clone() };
-        let response = send_and_recv(&mut self.stream, request)?;
-        if let Response::Err(_) = response {
-            return Ok(None);
+        match self.request(request)?.into_value() {
+            Err(KvsError::KeyNotFound) => Ok(None),
+            result => result.map(|_| Some(key)),
+        }
+    }
+
+    /// Get the values of many keys in a single round trip, in the order of `keys`.
+    /// The value of a key that does not exist is None.
+    pub fn mget(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.request(Request::MGet { keys })?.into_values()
+    }
+
+    /// Insert many key/values in a single round trip.
+    pub fn mset(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.request(Request::MSet { pairs })?.into_value()?;
+        Ok(())
+    }
+
+    /// Remove many keys in a single round trip, returning each removed key in the
+    /// order of `keys`, or None for the keys that did not exist.
+    pub fn mdel(&mut self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.request(Request::MDel { keys })?.into_values()
+    }
+
+    /// Move the keys from `start` to `end` excluded, or to the last one without an
+    /// end, to the server on `target`, returning how many keys were copied.
+    ///
+    /// The server keeps serving the keys while copying them, then redirects them
+    /// to `target`. The server needs an ACL, and the connection admin access to every key.
+    pub fn migrate(
+        &mut self,
+        start: String,
+        end: Option<String>,
+        target: impl Into<KvsAddr>,
+    ) -> Result<usize> {
+        let target = target.into().to_string();
+        let copied = self
+            .request(Request::Migrate { start, end, target })?
+            .into_value()?
+            .unwrap_or_default();
+        copied
+            .parse()
+            .map_err(|_| KvsError::StringError(format!("invalid number of keys: {}", copied)))
+    }
+
+    /// Check the server is alive.
+    pub fn ping(&mut self) -> Result<()> {
+        self.request(Request::Ping)?.into_value()?;
+        Ok(())
+    }
+
+    /// Describe the engine of the server, as `name:value` lines giving its name,
+    /// number of keys and size on the disk.
+    pub fn info(&mut self) -> Result<String> {
+        Ok(self
+            .request(Request::Info)?
+            .into_value()?
+            .unwrap_or_default())
+    }
+
+    /// Get the uptime of the server and its request counters, as `name:value` lines.
+    pub fn stats(&mut self) -> Result<String> {
+        Ok(self
+            .request(Request::Stats)?
+            .into_value()?
+            .unwrap_or_default())
+    }
+
+    /// Ask the server to compact the data of its engine now.
+    /// The connection needs admin access to every key.
+    pub fn compact(&mut self) -> Result<()> {
+        self.request(Request::Compact)?.into_value()?;
+        Ok(())
+    }
+
+    /// Remove every key of the server.
+    /// The connection needs admin access to every key, so the server needs an ACL.
+    pub fn flush_all(&mut self) -> Result<()> {
+        self.request(Request::FlushAll)?.into_value()?;
+        Ok(())
+    }
+
+    /// Get the last requests the server took longer than its threshold to run, oldest first.
+    /// The connection needs admin access to every key.
+    pub fn slowlog(&mut self) -> Result<Vec<SlowQuery>> {
+        self.request(Request::SlowLog)?.into_slowlog()
+    }
+
+    /// Follow the writes of the server, turning the connection into the stream of
+    /// replicated writes.
+    pub(crate) fn replicate(self) -> Result<impl Iterator<Item = Result<Feed>>> {
+        let stream = self.upgrade(Request::Replicate)?;
+        stream.set_read_timeout(Some(PRIMARY_TIMEOUT))?;
+        let feed = serde_json::Deserializer::from_reader(BufReader::new(stream));
+        Ok(feed
+            .into_iter()
+            .map(|message| message.map_err(KvsError::from)))
+    }
+
+    /// Send `request`, which turns the connection into another protocol once
+    /// answered, and take the connection.
+    pub(crate) fn upgrade(mut self, request: Request) -> Result<Stream> {
+        send_and_recv(&mut self.stream, &request)?.into_value()?;
+        Ok(self.stream)
+    }
+
+    /// Send `request`, following redirects and retrying on a new connection as the retry policy allows.
+    fn request(&mut self, request: Request) -> Result<Response> {
+        let mut attempt = 1;
+        let mut redirects = 0;
+        loop {
+            let err = match send_and_recv(&mut self.stream, &request) {
+                Ok(Response::Err(ErrorCode::Busy, message)) => KvsError::ServerBusy(message),
+                Ok(Response::Err(ErrorCode::Redirect, addr))
+                    if redirects < self.options.max_redirects =>
+                {
+                    redirects += 1;
+                    self.redirect(&addr)?;
+                    continue;
+                }
+                Ok(response) => return Ok(response),
+                Err(err) => err,
+            };
+            attempt = backoff(&self.options, attempt, Some(&request), err)?;
+            match open(&self.addr, &self.options) {
+                Ok(stream) => self.stream = stream,
+                // Sending on the stale connection fails again, and counts as another attempt.
+                Err(err) => warn!("failed to reconnect to {}: {}", self.addr, err),
+            }
+        }
+    }
+
+    /// Connect to `addr`, which the server redirected a request to.
+    fn redirect(&mut self, addr: &str) -> Result<()> {
+        let addr: KvsAddr = addr.parse()?;
+        info!("redirected to {}", addr);
+        self.stream = open(&addr, &self.options)?;
+        self.addr = addr;
+        Ok(())
+    }
+
+    /// Whether the connection can be used for another request after being idle.
+    pub(crate) fn is_reusable(&self) -> bool {
+        self.stream.is_reusable()
+    }
+}
+
+/// Connect to `addr`, and authenticate if `options` hold credentials.
+fn open(addr: &KvsAddr, options: &ClientOptions) -> Result<Stream> {
+    let mut stream = Stream::connect_timeout(addr, options.connect_timeout)?;
+    stream.set_read_timeout(options.read_timeout)?;
+    stream.set_write_timeout(options.write_timeout)?;
+    if let Some(ref tls) = options.tls {
+        stream = stream.connect_tls(tls.build()?, tls.server_name(addr)?)?;
+    }
+    if let Some((ref user, ref token)) = options.credentials {
+        let request = Request::Auth {
+            user: user.to_owned(),
+            token: token.to_owned(),
+        };
+        send_and_recv(&mut stream, &request)?.into_value()?;
+    }
+    Ok(stream)
+}
+
+/// Wait before the next attempt if the retry policy of `options` allows one after
+/// `err`, returning the number of the next attempt, or else give `err` back.
+fn backoff(
+    options: &ClientOptions,
+    attempt: u32,
+    request: Option<&Request>,
+    err: KvsError,
+) -> Result<u32> {
+    match options.retry {
+        Some(ref retry) if retry.should_retry(attempt, request, &err) => {
+            let delay = retry.delay(attempt);
+            warn!(
+                "attempt {} failed: {}, retrying in {:?}",
+                attempt, err, delay
+            );
+            thread::sleep(delay);
+            Ok(attempt + 1)
         }
-        Ok(Some(key))
+        _ => Err(err),
     }
 }
 
-fn send_and_recv(stream: &mut TcpStream, request: Request) -> Result<Response> {
-    let mut tcp_writer = stream.try_clone()?;
-    serde_json::to_writer(&mut tcp_writer, &request)?;
-    tcp_writer.flush()?;
+/// Send `request`, and read the response.
+fn send_and_recv(stream: &mut Stream, request: &Request) -> Result<Response> {
+    stream.write_all(&serde_json::to_vec(request)?)?;
+    stream.flush()?;
 
     let mut de = serde_json::Deserializer::from_reader(stream);
-    let response = Response::deserialize(&mut de)?;
+    let response = Response::deserialize(&mut de).map_err(|err| {
+        if err.is_eof() {
+            io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the server closed the connection",
+            )
+            .into()
+        } else {
+            KvsError::from(err)
+        }
+    })?;
     Ok(response)
 }
@@ -0,0 +1,203 @@
+use crate::engine::{KvsEngine, Result};
+
+/// Any engine, chosen at runtime, e.g. from an
+/// [`EngineRegistry`](crate::EngineRegistry) by name.
+///
+/// `KvsEngine` is not object safe since engines are `Clone`, so the engine is
+/// kept behind [`DynEngine`] which clones it into a new box.
+///
+/// # Examples
+/// ```rust
+/// # use kvs::{BoxedEngine, KvStore, KvsEngine, Result, SledKvStore};
+/// # use tempfile::TempDir;
+/// #
+/// # fn main() -> Result<()> {
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let use_sled = true;
+/// let store = if use_sled {
+///     BoxedEngine::new(SledKvStore::open(temp_dir.path())?)
+/// } else {
+///     BoxedEngine::new(KvStore::open(temp_dir.path())?)
+/// };
+/// store.set("Key1".to_owned(), "Value1".to_owned())?;
+/// assert_eq!(store.name(), "sled");
+/// # Ok(())
+/// # }
+/// ```
+pub struct BoxedEngine {
+    inner: Box<dyn DynEngine>,
+}
+
+impl BoxedEngine {
+    /// Box `engine`.
+    pub fn new<E: KvsEngine>(engine: E) -> Self {
+        BoxedEngine {
+            inner: Box::new(engine),
+        }
+    }
+}
+
+impl Clone for BoxedEngine {
+    fn clone(&self) -> Self {
+        BoxedEngine {
+            inner: self.inner.clone_box(),
+        }
+    }
+}
+
+/// The object-safe part of [`KvsEngine`], implemented for every engine.
+pub trait DynEngine: Send {
+    /// Clone the engine into a new box
+    fn clone_box(&self) -> Box<dyn DynEngine>;
+
+    /// See [`KvsEngine::set`]
+    fn set(&self, key: String, value: String) -> Result<()>;
+
+    /// See [`KvsEngine::get`]
+    fn get(&self, key: String) -> Result<Option<String>>;
+
+    /// See [`KvsEngine::remove`]
+    fn remove(&self, key: String) -> Result<()>;
+
+    /// See [`KvsEngine::flush`]
+    fn flush(&self) -> Result<()>;
+
+    /// See [`KvsEngine::scan`]
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()>;
+
+    /// See [`KvsEngine::name`]
+    fn name(&self) -> &'static str;
+
+    /// See [`KvsEngine::key_count`]
+    fn key_count(&self) -> Result<usize>;
+
+    /// See [`KvsEngine::disk_size`]
+    fn disk_size(&self) -> Result<u64>;
+
+    /// See [`KvsEngine::compact`]
+    fn compact(&self) -> Result<()>;
+
+    /// See [`KvsEngine::clear`]
+    fn clear(&self) -> Result<()>;
+
+    /// See [`KvsEngine::get_many`]
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>>;
+
+    /// See [`KvsEngine::set_many`]
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()>;
+
+    /// See [`KvsEngine::remove_many`]
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>>;
+}
+
+impl<E: KvsEngine> DynEngine for E {
+    fn clone_box(&self) -> Box<dyn DynEngine> {
+        Box::new(self.clone())
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        KvsEngine::set(self, key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        KvsEngine::get(self, key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        KvsEngine::remove(self, key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        KvsEngine::flush(self)
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        KvsEngine::scan(self, f)
+    }
+
+    fn name(&self) -> &'static str {
+        KvsEngine::name(self)
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        KvsEngine::key_count(self)
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        KvsEngine::disk_size(self)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvsEngine::compact(self)
+    }
+
+    fn clear(&self) -> Result<()> {
+        KvsEngine::clear(self)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        KvsEngine::get_many(self, keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        KvsEngine::set_many(self, pairs)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        KvsEngine::remove_many(self, keys)
+    }
+}
+
+impl KvsEngine for BoxedEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.inner.set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.inner.remove(key)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.inner.scan(f)
+    }
+
+    fn name(&self) -> &'static str {
+        self.inner.name()
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        self.inner.key_count()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.inner.disk_size()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.inner.compact()
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.inner.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.inner.set_many(pairs)
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.inner.remove_many(keys)
+    }
+}
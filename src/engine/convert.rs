@@ -0,0 +1,93 @@
+use crate::engine::{KvsEngine, Result};
+use crate::error::KvsError;
+use crate::shard::hash;
+use std::fmt;
+
+/// How many key/values are written to the target at once.
+const BATCH_SIZE: usize = 100;
+
+/// A summary of the content of an engine, which does not depend on the order
+/// the keys are scanned in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Checksum {
+    /// The number of keys.
+    pub keys: usize,
+    /// The sum of the hashes of the key/values.
+    pub digest: u64,
+}
+
+impl Checksum {
+    /// Compute the checksum of every key/value of `engine`.
+    pub fn of(engine: &impl KvsEngine) -> Result<Checksum> {
+        let mut checksum = Checksum::default();
+        engine.scan(&mut |key, value| {
+            checksum.add(&key, &value);
+            Ok(())
+        })?;
+        Ok(checksum)
+    }
+
+    fn add(&mut self, key: &str, value: &str) {
+        // The length of the key tells `ab` = `c` from `a` = `bc`.
+        let mut bytes = Vec::with_capacity(8 + key.len() + value.len());
+        bytes.extend_from_slice(&(key.len() as u64).to_le_bytes());
+        bytes.extend_from_slice(key.as_bytes());
+        bytes.extend_from_slice(value.as_bytes());
+        self.keys += 1;
+        self.digest = self.digest.wrapping_add(hash(&bytes));
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} keys, checksum {:016x}", self.keys, self.digest)
+    }
+}
+
+/// Copy every key/value of `source` into the empty engine `target`, e.g. to move
+/// the data of a [`KvStore`](crate::KvStore) to a [`SledKvStore`](crate::SledKvStore).
+///
+/// The copy is checked by comparing the checksums of both engines once it is
+/// flushed, which is returned. `source` must not be written to meanwhile.
+pub fn copy_engine(source: &impl KvsEngine, target: &impl KvsEngine) -> Result<Checksum> {
+    let existing = target.key_count()?;
+    if existing > 0 {
+        return Err(KvsError::StringError(format!(
+            "the target {} engine already holds {} keys",
+            target.name(),
+            existing
+        )));
+    }
+
+    let mut copied = Checksum::default();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    source.scan(&mut |key, value| {
+        copied.add(&key, &value);
+        batch.push((key, value));
+        if batch.len() == BATCH_SIZE {
+            target.set_many(batch.split_off(0))?;
+        }
+        Ok(())
+    })?;
+    target.set_many(batch)?;
+    target.flush()?;
+
+    let written = Checksum::of(target)?;
+    if written != copied {
+        return Err(KvsError::StorageError(format!(
+            "copied {} from the {} engine, but the {} engine holds {}",
+            copied,
+            source.name(),
+            target.name(),
+            written
+        )));
+    }
+    if target.key_count()? != copied.keys {
+        return Err(KvsError::StorageError(format!(
+            "the {} engine does not count the {} keys it holds",
+            target.name(),
+            copied.keys
+        )));
+    }
+    Ok(copied)
+}
@@ -16,8 +16,87 @@ pub trait KvsEngine: Clone + Send + 'static {
 
     /// Remove a given string key
     fn remove(&self, key: String) -> Result<()>;
+
+    /// Make sure all the writes so far are persisted to the disk,
+    /// nothing to do for engines persisting each write
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Call `f` on every key/value, in no particular order, stopping at the first error
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()>;
+
+    /// The name of the engine, e.g. `kvs` or `sled`
+    fn name(&self) -> &'static str {
+        "custom"
+    }
+
+    /// The number of keys
+    fn key_count(&self) -> Result<usize> {
+        let mut count = 0;
+        self.scan(&mut |_, _| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    /// The number of bytes the engine takes on the disk, 0 if unknown
+    fn disk_size(&self) -> Result<u64> {
+        Ok(0)
+    }
+
+    /// Reclaim the space taken by overwritten and removed values now
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Remove every key
+    fn clear(&self) -> Result<()> {
+        let mut keys = Vec::new();
+        self.scan(&mut |key, _| {
+            keys.push(key);
+            Ok(())
+        })?;
+        self.remove_many(keys)?;
+        Ok(())
+    }
+
+    /// Get the values of many keys, in the order of `keys`. The value of a key
+    /// that does not exist is None
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        keys.into_iter().map(|key| self.get(key)).collect()
+    }
+
+    /// Set the values of many keys, in order
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        for (key, value) in pairs {
+            self.set(key, value)?;
+        }
+        Ok(())
+    }
+
+    /// Remove many keys, returning whether each of them existed, in the order of `keys`
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        keys.into_iter()
+            .map(|key| match self.remove(key) {
+                Ok(()) => Ok(true),
+                Err(KvsError::KeyNotFound) => Ok(false),
+                Err(err) => Err(err),
+            })
+            .collect()
+    }
 }
 
+/// An engine chosen at runtime
+pub mod boxed;
+
+/// Copying the data of an engine to another one
+pub mod convert;
+
+/// Opening engines by name
+pub mod registry;
+
 /// A simple kv store using hash map store key/value
 pub mod simple_kvs;
 
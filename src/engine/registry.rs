@@ -0,0 +1,138 @@
+use crate::engine::boxed::BoxedEngine;
+use crate::engine::simple_kvs::{KvStore, KvStoreOptions};
+use crate::engine::sled_kvs::SledKvStore;
+use crate::engine::Result;
+use crate::error::KvsError;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Options passed to an [`EngineFactory`] opening an engine. Engines ignore
+/// the options that do not apply to them.
+#[derive(Debug, Clone, Default)]
+pub struct EngineOptions {
+    sync_writes: bool,
+    compaction_threshold: Option<u64>,
+}
+
+impl EngineOptions {
+    /// Sync every write to the disk, see [`KvStoreOptions::with_sync_writes`].
+    /// Defaults to false.
+    pub fn with_sync_writes(mut self, sync: bool) -> Self {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Compact once the engine holds more than `threshold` stale entries, see
+    /// [`KvStoreOptions::with_compaction_threshold`]. Defaults to the engine's own threshold.
+    pub fn with_compaction_threshold(mut self, threshold: u64) -> Self {
+        self.compaction_threshold = Some(threshold);
+        self
+    }
+
+    /// Whether every write should be synced to the disk.
+    pub fn sync_writes(&self) -> bool {
+        self.sync_writes
+    }
+
+    /// The number of stale entries triggering a compaction, if set.
+    pub fn compaction_threshold(&self) -> Option<u64> {
+        self.compaction_threshold
+    }
+}
+
+/// Opens an engine, for an [`EngineRegistry`].
+///
+/// It is implemented for the closures taking the same arguments as
+/// [`open`](EngineFactory::open).
+pub trait EngineFactory: Send + Sync {
+    /// Open the engine keeping its data in `dir`, which exists
+    fn open(&self, dir: &Path, options: &EngineOptions) -> Result<BoxedEngine>;
+}
+
+impl<F> EngineFactory for F
+where
+    F: Fn(&Path, &EngineOptions) -> Result<BoxedEngine> + Send + Sync,
+{
+    fn open(&self, dir: &Path, options: &EngineOptions) -> Result<BoxedEngine> {
+        self(dir, options)
+    }
+}
+
+/// The engines that can be opened by name, e.g. from the `--engine` flag of `kvs-server`.
+///
+/// The default registry holds `kvs`, a [`KvStore`], and `sled`, a [`SledKvStore`].
+///
+/// # Examples
+/// ```rust
+/// # use kvs::{BoxedEngine, EngineOptions, EngineRegistry, KvsEngine, Result, SledKvStore};
+/// # use tempfile::TempDir;
+/// #
+/// # fn main() -> Result<()> {
+/// let registry = EngineRegistry::default().with_engine("unsynced-sled", |dir: &std::path::Path, _: &EngineOptions| {
+///     Ok(BoxedEngine::new(SledKvStore::open(dir)?))
+/// });
+///
+/// let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+/// let store = registry.open("unsynced-sled", temp_dir.path(), &EngineOptions::default())?;
+/// store.set("Key1".to_owned(), "Value1".to_owned())?;
+/// assert!(registry.open("rocksdb", temp_dir.path(), &EngineOptions::default()).is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub struct EngineRegistry {
+    factories: BTreeMap<String, Box<dyn EngineFactory>>,
+}
+
+impl Default for EngineRegistry {
+    fn default() -> Self {
+        EngineRegistry::empty()
+            .with_engine("kvs", |dir: &Path, options: &EngineOptions| {
+                let mut kvs_options =
+                    KvStoreOptions::default().with_sync_writes(options.sync_writes);
+                if let Some(threshold) = options.compaction_threshold {
+                    kvs_options = kvs_options.with_compaction_threshold(threshold);
+                }
+                Ok(BoxedEngine::new(KvStore::open_with(dir, kvs_options)?))
+            })
+            .with_engine("sled", |dir: &Path, _: &EngineOptions| {
+                Ok(BoxedEngine::new(SledKvStore::open(dir)?))
+            })
+    }
+}
+
+impl EngineRegistry {
+    /// A registry without any engine.
+    pub fn empty() -> Self {
+        EngineRegistry {
+            factories: BTreeMap::new(),
+        }
+    }
+
+    /// Register `factory` as the engine `name`, replacing any engine of that name.
+    pub fn with_engine(
+        mut self,
+        name: impl Into<String>,
+        factory: impl EngineFactory + 'static,
+    ) -> Self {
+        self.factories.insert(name.into(), Box::new(factory));
+        self
+    }
+
+    /// The names of the registered engines, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.keys().map(String::as_str)
+    }
+
+    /// Open the engine `name` in `dir` with `options`.
+    pub fn open(&self, name: &str, dir: &Path, options: &EngineOptions) -> Result<BoxedEngine> {
+        let factory = self.factories.get(name).ok_or_else(|| {
+            let names: Vec<&str> = self.names().collect();
+            KvsError::StringError(format!(
+                "unknown engine {}, expected one of {}",
+                name,
+                names.join(", ")
+            ))
+        })?;
+        factory.open(dir, options)
+    }
+}
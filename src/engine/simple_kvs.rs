@@ -1,3 +1,5 @@
+use crate::lock::DirLock;
+use crate::metrics::METRICS;
 use crate::network::Request;
 use crate::Result;
 use crate::{KvsEngine, KvsError};
@@ -10,6 +12,7 @@ use std::io::{BufReader, BufWriter};
 use std::io::{Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 /// A simple kv store using hash map store key/value
 ///
@@ -44,16 +47,48 @@ use std::sync::{Arc, Mutex};
 #[derive(Clone)]
 pub struct KvStore {
     path: Arc<PathBuf>,
+    /// Keeps other stores from writing to the log meanwhile.
+    _lock: Arc<DirLock>,
     index: Arc<DashMap<String, u64>>,
     writer: Arc<Mutex<KvStoreWriter>>,
 }
 
+/// Options of a [`KvStore`].
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    sync_writes: bool,
+    compaction_threshold: u64,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            sync_writes: false,
+            compaction_threshold: 1000,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Sync the log to the disk after every write, so that it survives a crash
+    /// of the machine, not only of the process. Defaults to false.
+    pub fn with_sync_writes(mut self, sync: bool) -> Self {
+        self.sync_writes = sync;
+        self
+    }
+
+    /// Compact the log once it holds more than `threshold` stale entries. Defaults to 1000.
+    pub fn with_compaction_threshold(mut self, threshold: u64) -> Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+}
+
+/// The file locked by an open store.
+const LOCK_FILE: &str = "kvs.lock";
+
 fn new_buf_writer(path: &Path) -> Result<BufWriter<File>> {
-    let f = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(path)?;
+    let f = OpenOptions::new().create(true).append(true).open(path)?;
     Ok(BufWriter::new(f))
 }
 
@@ -75,26 +110,36 @@ impl KvStore {
     /// If no previous persisted log exists, create a new log;
     /// if there is a previous persisted log then create a
     /// KvStore based on the log.
+    ///
+    /// The store locks `path` until it and all its clones are dropped; opening
+    /// it again meanwhile fails with [`DirectoryLocked`](KvsError::DirectoryLocked).
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Create a KvStore at `path` with `options`, see [`KvStore::open`].
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
         let path: PathBuf = path.into();
+        let lock = Arc::new(DirLock::acquire(&path, LOCK_FILE)?);
         let (index, log_count) = KvStore::build_index(&path)?;
         let index = Arc::new(index);
 
         let path = Arc::new(path);
-        let kvs_writer = KvStoreWriter::new(path.clone(), index.clone(), log_count)?;
+        let kvs_writer = KvStoreWriter::new(path.clone(), index.clone(), log_count, options)?;
         let kv_store = KvStore {
             path,
+            _lock: lock,
             writer: Arc::new(Mutex::new(kvs_writer)),
             index,
         };
         Ok(kv_store)
     }
 
-    fn build_index(path: &PathBuf) -> Result<(DashMap<String, u64>, u64)> {
+    fn build_index(path: &Path) -> Result<(DashMap<String, u64>, u64)> {
         let index: DashMap<String, u64> = DashMap::new();
         let mut last_log_pointer = 0;
 
-        let file_path = log_path(&path, "log");
+        let file_path = log_path(path, "log");
         let mut reader = new_buf_reader(&file_path)?;
 
         let mut log_count = 0;
@@ -114,7 +159,7 @@ impl KvStore {
                     return Err(KvsError::NotValidLog);
                 }
             }
-            last_log_pointer = reader.seek(SeekFrom::Current(0))?;
+            last_log_pointer = reader.stream_position()?;
         }
 
         Ok((index, log_count))
@@ -125,7 +170,7 @@ impl KvsEngine for KvStore {
     fn set(&self, key: String, value: String) -> Result<()> {
         let mut writer = self.writer.lock().map_err(|err| err.to_string())?;
         writer.set(key, value)?;
-        writer.compact()?;
+        writer.maybe_compact()?;
         Ok(())
     }
 
@@ -134,13 +179,7 @@ impl KvsEngine for KvStore {
             Some(log_pointer) => {
                 let file_path = log_path(&self.path, "log");
                 let mut reader = new_buf_reader(&file_path)?;
-                reader.seek(SeekFrom::Start(log_pointer.to_owned()))?;
-                let deserialized = Document::from_reader(&mut reader)?;
-                let msg: Request = bson::from_document(deserialized)?;
-                match msg {
-                    Request::Set { key: _, ref value } => Ok(Some(value.to_owned())),
-                    _ => Err(KvsError::KeyNotFound),
-                }
+                read_value(&mut reader, *log_pointer).map(Some)
             }
             None => Ok(None),
         }
@@ -151,6 +190,97 @@ impl KvsEngine for KvStore {
         writer.remove(key)?;
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|err| err.to_string())?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        // Hold off compaction until the log is open, so that the pointers match it.
+        let (mut reader, pointers) = {
+            let _writer = self.writer.lock().map_err(|err| err.to_string())?;
+            let reader = new_buf_reader(&log_path(&self.path, "log"))?;
+            let pointers: Vec<(String, u64)> = self
+                .index
+                .iter()
+                .map(|entry| (entry.key().to_owned(), *entry.value()))
+                .collect();
+            (reader, pointers)
+        };
+        for (key, log_pointer) in pointers {
+            f(key, read_value(&mut reader, log_pointer)?)?;
+        }
+        Ok(())
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        // Hold off compaction until the log is open, as `scan` does.
+        let (mut reader, pointers) = {
+            let _writer = self.writer.lock().map_err(|err| err.to_string())?;
+            let reader = new_buf_reader(&log_path(&self.path, "log"))?;
+            let pointers: Vec<Option<u64>> = keys
+                .iter()
+                .map(|key| self.index.get(key).map(|entry| *entry.value()))
+                .collect();
+            (reader, pointers)
+        };
+        pointers
+            .into_iter()
+            .map(|pointer| match pointer {
+                Some(log_pointer) => read_value(&mut reader, log_pointer).map(Some),
+                None => Ok(None),
+            })
+            .collect()
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|err| err.to_string())?;
+        writer.set_many(pairs)?;
+        writer.maybe_compact()?;
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let mut writer = self.writer.lock().map_err(|err| err.to_string())?;
+        keys.into_iter()
+            .map(|key| match writer.remove(key) {
+                Ok(()) => Ok(true),
+                Err(KvsError::KeyNotFound) => Ok(false),
+                Err(err) => Err(err),
+            })
+            .collect()
+    }
+
+    fn name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        Ok(self.index.len())
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        let _writer = self.writer.lock().map_err(|err| err.to_string())?;
+        Ok(fs::metadata(log_path(&self.path, "log"))?.len())
+    }
+
+    fn compact(&self) -> Result<()> {
+        let mut writer = self.writer.lock().map_err(|err| err.to_string())?;
+        writer.compact()
+    }
+}
+
+/// Read the value set by the log entry at `log_pointer`.
+fn read_value(reader: &mut BufReader<File>, log_pointer: u64) -> Result<String> {
+    reader.seek(SeekFrom::Start(log_pointer))?;
+    let deserialized = Document::from_reader(reader)?;
+    let msg: Request = bson::from_document(deserialized)?;
+    match msg {
+        Request::Set { key: _, value } => Ok(value),
+        _ => Err(KvsError::KeyNotFound),
+    }
 }
 
 struct KvStoreWriter {
@@ -158,6 +288,7 @@ struct KvStoreWriter {
     writer: BufWriter<File>,
     index: Arc<DashMap<String, u64>>,
     log_count: u64,
+    options: KvStoreOptions,
 }
 
 impl KvStoreWriter {
@@ -165,6 +296,7 @@ impl KvStoreWriter {
         path: Arc<PathBuf>,
         index: Arc<DashMap<String, u64>>,
         log_count: u64,
+        options: KvStoreOptions,
     ) -> Result<Self> {
         let file_path = log_path(&path, "log");
         let writer = new_buf_writer(&file_path)?;
@@ -173,6 +305,7 @@ impl KvStoreWriter {
             writer,
             index,
             log_count,
+            options,
         };
         Ok(kvs_writer)
     }
@@ -185,55 +318,111 @@ impl KvStoreWriter {
         let set = Request::Set { key, value };
         let serialized = bson::to_document(&set)?;
         serialized.to_writer(&mut self.writer)?;
-        self.writer.flush()?;
+        self.commit()?;
+        Ok(())
+    }
+
+    /// Append all the sets with a single flush, and only then point the index to them.
+    fn set_many(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut log_pointer = self.writer.seek(SeekFrom::End(0))?;
+        let mut pointers = Vec::with_capacity(pairs.len());
+        for (key, value) in pairs {
+            let set = Request::Set {
+                key: key.clone(),
+                value,
+            };
+            let mut serialized = Vec::new();
+            bson::to_document(&set)?.to_writer(&mut serialized)?;
+            self.writer.write_all(&serialized)?;
+            pointers.push((key, log_pointer));
+            log_pointer += serialized.len() as u64;
+        }
+        self.commit()?;
+
+        self.log_count += pointers.len() as u64;
+        for (key, log_pointer) in pointers {
+            self.index.insert(key, log_pointer);
+        }
         Ok(())
     }
 
     fn remove(&mut self, key: String) -> Result<()> {
-        match self.index.get(&key) {
-            Some(_) => {
-                let rm = Request::Remove {
-                    key: key.to_owned(),
-                };
-                let serialized = bson::to_document(&rm)?;
-                serialized.to_writer(&mut self.writer)?;
-                self.writer.flush()?;
-                self.log_count -= 1;
-            }
-            None => return Err(KvsError::NotValidLog),
+        if !self.index.contains_key(&key) {
+            return Err(KvsError::KeyNotFound);
         }
+        let rm = Request::Remove {
+            key: key.to_owned(),
+        };
+        let serialized = bson::to_document(&rm)?;
+        serialized.to_writer(&mut self.writer)?;
+        self.commit()?;
+        self.log_count -= 1;
         self.index.remove(&key);
         Ok(())
     }
 
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+        Ok(())
+    }
+
+    /// Hand a write to the OS, and to the disk too with synced writes.
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.options.sync_writes {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Compact the log once it holds enough stale entries.
+    fn maybe_compact(&mut self) -> Result<()> {
+        let stale = self.log_count - self.index.len() as u64;
+        METRICS.garbage(stale, self.log_count);
+        if stale > self.options.compaction_threshold {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log with only the live entries.
     fn compact(&mut self) -> Result<()> {
-        if self.log_count - self.index.len() as u64 > 1000 {
-            let file_path = log_path(&self.path, "tmp");
-            let mut writer = new_buf_writer(&file_path)?;
-
-            let file_path = log_path(&self.path, "log");
-            let mut reader = new_buf_reader(&file_path)?;
-            let mut log_count: u64 = 0;
-            let mut last_log_pointer = 0;
-            let mut index = HashMap::new();
-            for entry in self.index.iter() {
-                reader.seek(SeekFrom::Start(entry.value().to_owned()))?;
-                let deserialized = Document::from_reader(&mut reader)?;
-                deserialized.to_writer(&mut writer)?;
-                writer.flush()?;
-                index.insert(entry.key().to_owned(), last_log_pointer);
-                last_log_pointer = writer.seek(SeekFrom::Current(0))?;
-                log_count += 1;
-            }
+        let started = Instant::now();
+        METRICS.compaction_started();
+        let result = self.rewrite();
+        METRICS.compaction_finished(started.elapsed());
+        result?;
+        METRICS.garbage(0, self.log_count);
+        Ok(())
+    }
 
-            self.log_count = log_count;
-            fs::rename(self.path.join("tmp.bson"), self.path.join("log.bson"))?;
-            let writer = new_buf_writer(&file_path)?;
-            self.writer = writer;
-            self.index.clear();
-            for (key, value) in index {
-                self.index.insert(key, value);
-            }
+    fn rewrite(&mut self) -> Result<()> {
+        let file_path = log_path(&self.path, "tmp");
+        let mut writer = new_buf_writer(&file_path)?;
+
+        let file_path = log_path(&self.path, "log");
+        let mut reader = new_buf_reader(&file_path)?;
+        let mut log_count: u64 = 0;
+        let mut last_log_pointer = 0;
+        let mut index = HashMap::new();
+        for entry in self.index.iter() {
+            reader.seek(SeekFrom::Start(entry.value().to_owned()))?;
+            let deserialized = Document::from_reader(&mut reader)?;
+            deserialized.to_writer(&mut writer)?;
+            writer.flush()?;
+            index.insert(entry.key().to_owned(), last_log_pointer);
+            last_log_pointer = writer.stream_position()?;
+            log_count += 1;
+        }
+
+        self.log_count = log_count;
+        fs::rename(self.path.join("tmp.bson"), self.path.join("log.bson"))?;
+        let writer = new_buf_writer(&file_path)?;
+        self.writer = writer;
+        self.index.clear();
+        for (key, value) in index {
+            self.index.insert(key, value);
         }
 
         Ok(())
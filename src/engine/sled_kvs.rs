@@ -1,6 +1,7 @@
 use crate::engine::KvsEngine;
 use crate::engine::Result;
 use crate::error::KvsError;
+use sled::transaction::TransactionError;
 use std::path::PathBuf;
 
 /// A kv store using the `sled` library
@@ -76,4 +77,64 @@ impl KvsEngine for SledKvStore {
         }
         Ok(())
     }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        for entry in self.db.iter() {
+            let (key, value) = entry?;
+            f(
+                String::from_utf8(key.to_vec())?,
+                String::from_utf8(value.to_vec())?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (key, value) in pairs {
+            batch.insert(key.as_bytes(), value.as_bytes());
+        }
+        self.db.apply_batch(batch)?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        // A batch does not tell which keys existed, so check them in a transaction.
+        let removed = self.db.transaction(|db| {
+            let mut removed = Vec::with_capacity(keys.len());
+            for key in &keys {
+                removed.push(db.remove(key.as_bytes())?.is_some());
+            }
+            Ok(removed)
+        });
+        let removed = removed.map_err(|err: TransactionError| match err {
+            TransactionError::Storage(err) | TransactionError::Abort(err) => KvsError::from(err),
+        })?;
+        self.db.flush()?;
+        Ok(removed)
+    }
+
+    fn name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        Ok(self.db.len())
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        Ok(self.db.size_on_disk()?)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.db.clear()?;
+        self.db.flush()?;
+        Ok(())
+    }
 }
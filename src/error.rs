@@ -1,5 +1,6 @@
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use std::string::FromUtf8Error;
 
 /// Possible errors that can arise.
@@ -20,6 +21,9 @@ pub enum KvsError {
     /// A [`sled::Error`](https://docs.rs/sled/0.16.2/sled/enum.Error.html) encountered while using sled.
     SledError(sled::Error),
 
+    /// A [`rustls::Error`](https://docs.rs/rustls/0.23/rustls/enum.Error.html) encountered while setting up TLS.
+    TlsError(rustls::Error),
+
     /// A [`std::string::FromUtf8Error`](https://doc.rust-lang.org/std/string/struct.FromUtf8Error.html) encountered
     /// while decoding a UTF-8 String from the input data.
     FromUtf8Error(FromUtf8Error),
@@ -35,6 +39,27 @@ pub enum KvsError {
 
     /// Raise when input engine mismatch the previous persisted engine.
     MismatchEngine,
+
+    /// Raise when a directory is already opened by another store or server, with its path.
+    DirectoryLocked(PathBuf),
+
+    /// Raise when the server rejects the credentials or the rights of a client.
+    Unauthorized(String),
+
+    /// Raise when the server could not understand a request.
+    InvalidRequest(String),
+
+    /// Raise when the server failed to read or write its data.
+    StorageError(String),
+
+    /// Raise when the server is too busy to handle a request.
+    ServerBusy(String),
+
+    /// Raise when the server failed to handle a request for any other reason.
+    ServerError(String),
+
+    /// Raise when a write is sent to a replica, with the address of the server to send it to.
+    Redirect(String),
 }
 
 impl fmt::Display for KvsError {
@@ -45,11 +70,23 @@ impl fmt::Display for KvsError {
             KvsError::BsonDeError(ref err) => err.fmt(f),
             KvsError::SerdeJsonError(ref err) => err.fmt(f),
             KvsError::SledError(ref err) => err.fmt(f),
+            KvsError::TlsError(ref err) => err.fmt(f),
             KvsError::FromUtf8Error(ref err) => err.fmt(f),
             KvsError::StringError(ref err) => write!(f, "{}", err),
             KvsError::KeyNotFound => write!(f, "Key not found"),
             KvsError::NotValidLog => write!(f, "Not valid log"),
             KvsError::MismatchEngine => write!(f, "Mismatch engine"),
+            KvsError::DirectoryLocked(ref path) => write!(
+                f,
+                "{} is already in use by another store or server",
+                path.display()
+            ),
+            KvsError::Unauthorized(ref message) => write!(f, "Unauthorized: {}", message),
+            KvsError::InvalidRequest(ref message) => write!(f, "Invalid request: {}", message),
+            KvsError::StorageError(ref message) => write!(f, "Storage error: {}", message),
+            KvsError::ServerBusy(ref message) => write!(f, "Server busy: {}", message),
+            KvsError::ServerError(ref message) => write!(f, "Server error: {}", message),
+            KvsError::Redirect(ref addr) => write!(f, "Redirected to {}", addr),
         }
     }
 }
@@ -74,7 +111,12 @@ impl From<bson::de::Error> for KvsError {
 
 impl From<serde_json::Error> for KvsError {
     fn from(err: serde_json::Error) -> KvsError {
-        KvsError::SerdeJsonError(err)
+        // Failing to read or write the bytes is not a JSON error.
+        if err.is_io() {
+            KvsError::IoError(err.into())
+        } else {
+            KvsError::SerdeJsonError(err)
+        }
     }
 }
 
@@ -84,6 +126,12 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rustls::Error> for KvsError {
+    fn from(err: rustls::Error) -> KvsError {
+        KvsError::TlsError(err)
+    }
+}
+
 impl From<FromUtf8Error> for KvsError {
     fn from(err: FromUtf8Error) -> KvsError {
         KvsError::FromUtf8Error(err)
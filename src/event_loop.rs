@@ -0,0 +1,518 @@
+//! A readiness-based event loop serving many connections from a single thread.
+//!
+//! The loop reads the bytes clients send without blocking, and only hands complete
+//! requests to the thread pool, so that slow clients do not tie up pool threads.
+//! A connection has at most one request running at a time, requests sent ahead
+//! being buffered until the previous response is written.
+use crate::auth::Acl;
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::limits::{Limits, LINGER, MAX_MESSAGE_SIZE};
+use crate::network::{MessageBuf, Request, Response};
+use crate::server::{process_cmd, Session};
+use crate::shutdown::ShutdownHandle;
+use crate::stats::Stats;
+use crate::thread_pool::ThreadPool;
+use crate::transport::Listener;
+use crate::KvsEngine;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const READ_CHUNK: usize = 4096;
+
+enum MioListener {
+    Tcp(mio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(mio::net::UnixListener),
+}
+
+impl MioListener {
+    fn from_std(listener: Listener) -> io::Result<MioListener> {
+        match listener {
+            Listener::Tcp(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(MioListener::Tcp(mio::net::TcpListener::from_std(listener)))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                listener.set_nonblocking(true)?;
+                Ok(MioListener::Unix(mio::net::UnixListener::from_std(
+                    listener,
+                )))
+            }
+        }
+    }
+
+    fn register(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            MioListener::Tcp(listener) => registry.register(listener, LISTENER, Interest::READABLE),
+            #[cfg(unix)]
+            MioListener::Unix(listener) => {
+                registry.register(listener, LISTENER, Interest::READABLE)
+            }
+        }
+    }
+
+    /// Accept a new connection, along with a printable description of the remote end.
+    fn accept(&self) -> io::Result<(MioStream, String)> {
+        match self {
+            MioListener::Tcp(listener) => listener
+                .accept()
+                .map(|(stream, addr)| (MioStream::Tcp(stream), addr.to_string())),
+            #[cfg(unix)]
+            MioListener::Unix(listener) => listener
+                .accept()
+                .map(|(stream, _)| (MioStream::Unix(stream), "unix socket".to_owned())),
+        }
+    }
+}
+
+enum MioStream {
+    Tcp(mio::net::TcpStream),
+    #[cfg(unix)]
+    Unix(mio::net::UnixStream),
+}
+
+impl MioStream {
+    fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            MioStream::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            MioStream::Unix(stream) => stream.shutdown(Shutdown::Write),
+        }
+    }
+
+    fn register(&mut self, registry: &Registry, token: Token) -> io::Result<()> {
+        let interest = Interest::READABLE | Interest::WRITABLE;
+        match self {
+            MioStream::Tcp(stream) => registry.register(stream, token, interest),
+            #[cfg(unix)]
+            MioStream::Unix(stream) => registry.register(stream, token, interest),
+        }
+    }
+}
+
+impl Read for MioStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            MioStream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            MioStream::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for MioStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            MioStream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            MioStream::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            MioStream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            MioStream::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A client connection, and the bytes buffered in each direction.
+struct Connection {
+    stream: MioStream,
+    peer: String,
+    input: MessageBuf,
+    output: Vec<u8>,
+    /// `None` while a request of the connection runs on the pool.
+    session: Option<Session>,
+    /// Whether the client closed its side of the connection.
+    closed: bool,
+    /// The last time bytes were read or written, or a request completed.
+    since: Instant,
+    /// Whether the client sent a malformed request: its input is discarded, and
+    /// the connection closed once the error response is sent.
+    failed: bool,
+    /// When the write half was shut down after a malformed request, see [`Stream::linger`](crate::transport::Stream).
+    lingering: Option<Instant>,
+}
+
+impl Connection {
+    fn is_idle(&self) -> bool {
+        self.session.is_some() && self.output.is_empty()
+    }
+
+    /// When the connection times out in its current state, and which timeout it is.
+    fn deadline(&self, limits: &Limits) -> Option<(Instant, &'static str)> {
+        let (timeout, name) = if let Some(lingering) = self.lingering {
+            return Some((lingering + LINGER, "linger"));
+        } else if self.session.is_none() {
+            // A request runs on the pool, the client is not waited for.
+            return None;
+        } else if !self.output.is_empty() {
+            (limits.write_timeout?, "write")
+        } else if self.input.is_blank() {
+            (limits.idle_timeout?, "idle")
+        } else {
+            (limits.read_timeout?, "read")
+        };
+        Some((self.since + timeout, name))
+    }
+
+    /// Read everything available, until the socket would block.
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; READ_CHUNK];
+        loop {
+            match self.stream.read(&mut chunk) {
+                Ok(0) => {
+                    self.closed = true;
+                    return Ok(());
+                }
+                Ok(n) => {
+                    self.input.extend(&chunk[..n]);
+                    self.since = Instant::now();
+                    // The rest is not read, the connection is failed.
+                    if self.input.len() > MAX_MESSAGE_SIZE {
+                        return Ok(());
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Write as much of the pending output as the socket accepts.
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.output.drain(..n);
+                    self.since = Instant::now();
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A request done on the pool, to be answered by the loop.
+struct Completion {
+    token: Token,
+    session: Session,
+    response: Response,
+}
+
+pub(crate) struct EventLoop<E: KvsEngine, T: ThreadPool> {
+    poll: Poll,
+    listener: MioListener,
+    waker: Arc<Waker>,
+    sender: mpsc::Sender<Completion>,
+    receiver: mpsc::Receiver<Completion>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    store: E,
+    pool: Arc<T>,
+    acl: Option<Arc<Acl>>,
+    stats: Arc<Stats>,
+    limits: Limits,
+    stopping: bool,
+}
+
+impl<E: KvsEngine, T: ThreadPool> EventLoop<E, T> {
+    pub(crate) fn new(
+        listener: Listener,
+        store: E,
+        pool: Arc<T>,
+        acl: Option<Arc<Acl>>,
+        stats: Arc<Stats>,
+        limits: Limits,
+    ) -> Result<Self> {
+        let poll = Poll::new()?;
+        let mut listener = MioListener::from_std(listener)?;
+        listener.register(poll.registry())?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, receiver) = mpsc::channel();
+        Ok(EventLoop {
+            poll,
+            listener,
+            waker,
+            sender,
+            receiver,
+            connections: HashMap::new(),
+            next_token: WAKER.0 + 1,
+            store,
+            pool,
+            acl,
+            stats,
+            limits,
+            stopping: false,
+        })
+    }
+
+    /// Serve the connections until `shutdown`, or until a message is received on `legacy`.
+    ///
+    /// Once stopping, no new connection is accepted nor request started, and the
+    /// loop returns when the requests in flight are answered or `timeout` elapses.
+    pub(crate) fn run(
+        &mut self,
+        shutdown: &ShutdownHandle,
+        legacy: Option<&mpsc::Receiver<()>>,
+        timeout: Duration,
+    ) -> Result<()> {
+        let mut events = Events::with_capacity(1024);
+        let mut deadline = None;
+        loop {
+            let wake_at = self.next_deadline().into_iter().chain(deadline).min();
+            let wait = wake_at.map(|at| at.saturating_duration_since(Instant::now()));
+            if let Err(err) = self.poll.poll(&mut events, wait) {
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => {
+                        let requested = shutdown.is_shutdown()
+                            || legacy.is_some_and(|rx| rx.try_recv().is_ok());
+                        if requested {
+                            self.stopping = true;
+                        } else {
+                            self.accept()?;
+                        }
+                    }
+                    WAKER => {}
+                    token => {
+                        if event.is_readable() || event.is_read_closed() {
+                            self.read(token);
+                        }
+                        self.advance(token);
+                    }
+                }
+            }
+            // The waker may have been coalesced with other events, check every time.
+            while let Ok(completion) = self.receiver.try_recv() {
+                self.complete(completion);
+            }
+            self.close_timed_out();
+            if shutdown.is_shutdown() {
+                self.stopping = true;
+            }
+
+            if self.stopping {
+                self.connections
+                    .retain(|_, connection| !connection.is_idle());
+                if self.connections.is_empty() {
+                    return Ok(());
+                }
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + timeout);
+                if Instant::now() >= deadline {
+                    warn!(
+                        "{} connections still running after {:?}",
+                        self.connections.len(),
+                        timeout
+                    );
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// The earliest time a connection times out.
+    fn next_deadline(&self) -> Option<Instant> {
+        self.connections
+            .values()
+            .filter_map(|connection| connection.deadline(&self.limits))
+            .map(|(deadline, _)| deadline)
+            .min()
+    }
+
+    fn close_timed_out(&mut self) {
+        let now = Instant::now();
+        let limits = self.limits;
+        self.connections
+            .retain(|_, connection| match connection.deadline(&limits) {
+                Some((deadline, timeout)) if deadline <= now => {
+                    warn!(
+                        "closing connection from {}: {} timeout",
+                        connection.peer, timeout
+                    );
+                    false
+                }
+                _ => true,
+            });
+    }
+
+    fn accept(&mut self) -> Result<()> {
+        loop {
+            let (mut stream, peer) = match self.listener.accept() {
+                Ok(accepted) => accepted,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) => {
+                    error!("accept error: {}", err);
+                    return Ok(());
+                }
+            };
+            if self.limits.is_full(self.connections.len()) {
+                warn!("refusing connection from {}: too many connections", peer);
+                // Best effort: the socket is new, the response fits in its buffer.
+                let response: Response =
+                    KvsError::ServerBusy("Too many connections".to_owned()).into();
+                if let Ok(bytes) = serde_json::to_vec(&response) {
+                    let _ = stream.write(&bytes);
+                }
+                continue;
+            }
+
+            info!("connection from {}", peer);
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            stream.register(self.poll.registry(), token)?;
+            let connection = Connection {
+                stream,
+                peer: peer.clone(),
+                input: MessageBuf::new(),
+                output: Vec::new(),
+                session: Some(Session::new(self.acl.clone(), self.stats.clone(), peer)),
+                closed: false,
+                since: Instant::now(),
+                failed: false,
+                lingering: None,
+            };
+            self.connections.insert(token, connection);
+        }
+    }
+
+    fn read(&mut self, token: Token) {
+        if let Some(connection) = self.connections.get_mut(&token) {
+            if connection.closed {
+                return;
+            }
+            if let Err(err) = connection.fill() {
+                error!("connection from {} failed: {}", connection.peer, err);
+                self.connections.remove(&token);
+            } else if connection.failed {
+                connection.input.clear();
+            } else if connection.input.len() > MAX_MESSAGE_SIZE && connection.session.is_none() {
+                // The requests cannot be refused in order while one runs.
+                warn!(
+                    "closing connection from {}: more than {} bytes of requests",
+                    connection.peer, MAX_MESSAGE_SIZE
+                );
+                self.connections.remove(&token);
+            }
+        }
+    }
+
+    /// Start the next buffered request if the connection is free, write what is
+    /// pending, and drop the connection once the client is gone and everything is answered.
+    fn advance(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let result = if self.stopping || connection.failed || connection.session.is_none() {
+            Ok(None)
+        } else if connection.input.len() > MAX_MESSAGE_SIZE {
+            Err(KvsError::InvalidRequest(format!(
+                "requests larger than {} bytes",
+                MAX_MESSAGE_SIZE
+            )))
+        } else {
+            connection.input.decode::<Request>()
+        };
+        match result {
+            Ok(Some(request)) => {
+                if let Some(session) = connection.session.take() {
+                    self.dispatch(token, session, request);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => {
+                // The rest of the stream cannot be trusted, close the connection once answered.
+                connection.input.clear();
+                connection.failed = true;
+                let response = match connection.session {
+                    Some(ref session) => session.reject(err),
+                    None => None,
+                };
+                let written = response.map(|response| {
+                    serde_json::to_writer(&mut connection.output, &response).is_ok()
+                });
+                if written != Some(true) {
+                    self.connections.remove(&token);
+                    return;
+                }
+            }
+        }
+
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if let Err(err) = connection.drain() {
+            error!("connection from {} failed: {}", connection.peer, err);
+            self.connections.remove(&token);
+            return;
+        }
+        if connection.failed && connection.output.is_empty() && connection.lingering.is_none() {
+            if connection.stream.shutdown_write().is_err() {
+                self.connections.remove(&token);
+                return;
+            }
+            connection.lingering = Some(Instant::now());
+        }
+        if connection.closed && connection.is_idle() {
+            self.connections.remove(&token);
+        }
+    }
+
+    /// Run `request` on the pool, and wake the loop up once it is done.
+    fn dispatch(&self, token: Token, mut session: Session, request: Request) {
+        let store = self.store.clone();
+        let sender = self.sender.clone();
+        let waker = self.waker.clone();
+        self.pool.spawn(move || {
+            let response = process_cmd(&store, &mut session, request);
+            let completion = Completion {
+                token,
+                session,
+                response,
+            };
+            // The loop is gone if the server stopped before the request was done.
+            if sender.send(completion).is_ok() {
+                let _ = waker.wake();
+            }
+        });
+    }
+
+    fn complete(&mut self, completion: Completion) {
+        let connection = match self.connections.get_mut(&completion.token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        connection.session = Some(completion.session);
+        connection.since = Instant::now();
+        if let Err(err) = serde_json::to_writer(&mut connection.output, &completion.response) {
+            error!("connection from {} failed: {}", connection.peer, err);
+            self.connections.remove(&completion.token);
+            return;
+        }
+        self.advance(completion.token);
+    }
+}
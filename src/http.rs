@@ -0,0 +1,420 @@
+//! A minimal HTTP/1.1 gateway exposing the engine as a REST resource.
+//!
+//! Supported routes:
+//!
+//! * `GET /keys/{key}` returns `{"key": .., "value": ..}`, or 404 if the key does not exist.
+//! * `PUT /keys/{key}` stores the request body as the value of `key`.
+//! * `DELETE /keys/{key}` removes `key`, or returns 404 if the key does not exist.
+//!
+//! Errors are reported as `{"error": ..}` JSON bodies. When the server has an ACL,
+//! requests must carry the user name and token with basic authentication.
+//!
+//! The metrics endpoint is served separately, see [`serve_metrics`].
+use crate::auth::{Access, Acl};
+use crate::engine::Result;
+use crate::limits::MAX_MESSAGE_SIZE;
+use crate::metrics::METRICS;
+use crate::network::ErrorCode;
+use crate::shutdown::{Connections, ShutdownHandle};
+use crate::thread_pool::ThreadPool;
+use crate::transport::Stream;
+use crate::KvsEngine;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::Duration;
+
+const KEYS_PREFIX: &str = "/keys/";
+const MAX_HEADERS: usize = 100;
+/// The longest request line or header, in bytes.
+const MAX_LINE: u64 = 8 * 1024;
+const METRICS_PATH: &str = "/metrics";
+/// How long the gateway waits for a client to send a request or accept a response.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the metrics endpoint waits for a scraper, which holds it meanwhile.
+const METRICS_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct HttpRequest {
+    method: String,
+    path: String,
+    credentials: Option<(String, String)>,
+    content_length: usize,
+    body: Vec<u8>,
+}
+
+struct HttpResponse {
+    status: u16,
+    reason: &'static str,
+    headers: Vec<(&'static str, &'static str)>,
+    content_type: &'static str,
+    body: Option<String>,
+}
+
+impl HttpResponse {
+    fn no_content() -> Self {
+        HttpResponse {
+            status: 204,
+            reason: "No Content",
+            headers: Vec::new(),
+            content_type: "application/json",
+            body: None,
+        }
+    }
+
+    fn json(status: u16, reason: &'static str, body: serde_json::Value) -> Self {
+        HttpResponse {
+            status,
+            reason,
+            headers: Vec::new(),
+            content_type: "application/json",
+            body: Some(body.to_string()),
+        }
+    }
+
+    fn text(body: String) -> Self {
+        HttpResponse {
+            status: 200,
+            reason: "OK",
+            headers: Vec::new(),
+            content_type: "text/plain; version=0.0.4",
+            body: Some(body),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str, message: &str) -> Self {
+        HttpResponse::json(status, reason, json!({ "error": message }))
+    }
+
+    fn with_header(mut self, name: &'static str, value: &'static str) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+/// Accept HTTP connections on `listener` and handle each of them on `pool`, until `shutdown`.
+pub(crate) fn serve<E, T>(
+    listener: TcpListener,
+    store: E,
+    pool: Arc<T>,
+    acl: Option<Arc<Acl>>,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+) where
+    E: KvsEngine,
+    T: ThreadPool,
+{
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("http accept error: {}", err);
+                continue;
+            }
+        };
+        let connection = match stream.try_clone() {
+            Ok(socket) => connections.register(Stream::Tcp(socket)),
+            Err(err) => {
+                error!("http accept error: {}", err);
+                continue;
+            }
+        };
+        if let Err(err) = set_timeouts(&stream, HTTP_TIMEOUT) {
+            error!("http accept error: {}", err);
+            continue;
+        }
+        let store = store.clone();
+        let acl = acl.clone();
+        pool.spawn(move || {
+            let _connection = connection;
+            let peer = stream.peer_addr();
+            if let Err(err) = handle_connection(stream, store, acl) {
+                error!("http connection from {:?} failed: {}", peer, err);
+            }
+        });
+    }
+}
+
+/// Answer `GET /metrics` with the metrics of the process in the Prometheus text
+/// format, on the connections of `listener` until `shutdown`.
+///
+/// Scrapes are rare and quick, so they are answered on the calling thread rather
+/// than waiting behind the requests queued on the pool.
+pub(crate) fn serve_metrics(
+    listener: TcpListener,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+) {
+    for stream in listener.incoming() {
+        if shutdown.is_shutdown() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("metrics accept error: {}", err);
+                continue;
+            }
+        };
+        let _connection = match stream.try_clone() {
+            Ok(socket) => connections.register(Stream::Tcp(socket)),
+            Err(err) => {
+                error!("metrics accept error: {}", err);
+                continue;
+            }
+        };
+        let peer = stream.peer_addr();
+        let result = set_timeouts(&stream, METRICS_TIMEOUT).and_then(|_| {
+            let mut reader = BufReader::new(stream);
+            // The body of a scrape, if any, is not read: the connection is closed after the response.
+            let response = match read_head(&mut reader)? {
+                Ok(ref request) if request.path != METRICS_PATH => {
+                    HttpResponse::error(404, "Not Found", "Unknown path")
+                }
+                Ok(ref request) if request.method != "GET" => {
+                    HttpResponse::error(405, "Method Not Allowed", "Method not allowed")
+                        .with_header("Allow", "GET")
+                }
+                Ok(_) => HttpResponse::text(METRICS.render()),
+                Err(response) => response,
+            };
+            write_response(reader.get_mut(), response)
+        });
+        if let Err(err) = result {
+            error!("metrics connection from {:?} failed: {}", peer, err);
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    store: impl KvsEngine,
+    acl: Option<Arc<Acl>>,
+) -> Result<()> {
+    let mut reader = BufReader::new(stream);
+    let response = match read_request(&mut reader)? {
+        Ok(request) => {
+            info!("http {} {}", request.method, request.path);
+            process_request(store, acl.as_deref(), request)
+        }
+        Err(response) => response,
+    };
+    write_response(reader.get_mut(), response)
+}
+
+fn set_timeouts(stream: &TcpStream, timeout: Duration) -> Result<()> {
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(())
+}
+
+/// A request, or the error response to send if it is not valid or too large.
+type Parsed = std::result::Result<HttpRequest, HttpResponse>;
+
+/// Read one request with its body.
+fn read_request(reader: &mut impl BufRead) -> Result<Parsed> {
+    let mut request = match read_head(reader)? {
+        Ok(request) => request,
+        Err(response) => return Ok(Err(response)),
+    };
+    if request.content_length > MAX_MESSAGE_SIZE {
+        return Ok(Err(HttpResponse::error(
+            413,
+            "Payload Too Large",
+            "Request body too large",
+        )));
+    }
+    request.body = vec![0; request.content_length];
+    reader.read_exact(&mut request.body)?;
+    Ok(Ok(request))
+}
+
+/// Read the request line and headers of one request, leaving its body unread.
+fn read_head(reader: &mut impl BufRead) -> Result<Parsed> {
+    let malformed = || {
+        Err(HttpResponse::error(
+            400,
+            "Bad Request",
+            "Malformed HTTP request",
+        ))
+    };
+    let mut line = String::new();
+    if !read_line(reader, &mut line)? {
+        return Ok(malformed());
+    }
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(path), Some(version)) if version.starts_with("HTTP/") => {
+            (method.to_owned(), path.to_owned())
+        }
+        _ => return Ok(malformed()),
+    };
+
+    let mut content_length = 0;
+    let mut credentials = None;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if !read_line(reader, &mut line)? {
+            return Ok(malformed());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            return Ok(Ok(HttpRequest {
+                method,
+                path,
+                credentials,
+                content_length,
+                body: Vec::new(),
+            }));
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let name = name.trim();
+            if name.eq_ignore_ascii_case("content-length") {
+                match value.trim().parse() {
+                    Ok(length) => content_length = length,
+                    Err(_) => return Ok(malformed()),
+                }
+            } else if name.eq_ignore_ascii_case("authorization") {
+                credentials = basic_credentials(value.trim());
+            }
+        }
+    }
+    Ok(malformed())
+}
+
+/// Read a line of at most `MAX_LINE` bytes, returning whether it is complete.
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<bool> {
+    reader.take(MAX_LINE).read_line(line)?;
+    Ok(line.ends_with('\n'))
+}
+
+fn process_request(store: impl KvsEngine, acl: Option<&Acl>, request: HttpRequest) -> HttpResponse {
+    let key = match request.path.strip_prefix(KEYS_PREFIX).map(percent_decode) {
+        Some(Some(key)) if !key.is_empty() => key,
+        Some(_) => return HttpResponse::error(400, "Bad Request", "Invalid key"),
+        None => return HttpResponse::error(404, "Not Found", "Unknown path"),
+    };
+
+    if let Some(acl) = acl {
+        let user = match request.credentials {
+            Some((ref user, ref token)) if acl.authenticate(user, token) => user,
+            _ => {
+                return HttpResponse::error(401, "Unauthorized", "Authentication required")
+                    .with_header("WWW-Authenticate", "Basic realm=\"kvs\"")
+            }
+        };
+        let access = match request.method.as_str() {
+            "GET" => Access::Read,
+            _ => Access::Write,
+        };
+        if !acl.is_allowed(user, &key, access) {
+            return HttpResponse::error(403, "Forbidden", "Access denied");
+        }
+    }
+
+    let result = match request.method.as_str() {
+        "GET" => store.get(key.clone()).map(|value| match value {
+            Some(value) => HttpResponse::json(200, "OK", json!({ "key": key, "value": value })),
+            None => HttpResponse::error(404, "Not Found", "Key not found"),
+        }),
+        "PUT" => match String::from_utf8(request.body) {
+            Ok(value) => store.set(key, value).map(|_| HttpResponse::no_content()),
+            Err(_) => Ok(HttpResponse::error(
+                400,
+                "Bad Request",
+                "Value is not valid UTF-8",
+            )),
+        },
+        "DELETE" => store.remove(key).map(|_| HttpResponse::no_content()),
+        _ => Ok(
+            HttpResponse::error(405, "Method Not Allowed", "Method not allowed")
+                .with_header("Allow", "GET, PUT, DELETE"),
+        ),
+    };
+
+    result.unwrap_or_else(|err| {
+        let (status, reason) = match ErrorCode::from(&err) {
+            ErrorCode::NotFound => (404, "Not Found"),
+            ErrorCode::InvalidRequest => (400, "Bad Request"),
+            ErrorCode::Unauthorized => (403, "Forbidden"),
+            ErrorCode::Busy => (503, "Service Unavailable"),
+            ErrorCode::Redirect => (421, "Misdirected Request"),
+            ErrorCode::StorageError | ErrorCode::Internal => {
+                error!("http request failed: {}", err);
+                (500, "Internal Server Error")
+            }
+        };
+        HttpResponse::error(status, reason, &err.to_string())
+    })
+}
+
+fn write_response(stream: &mut impl Write, response: HttpResponse) -> Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\n",
+        response.status, response.reason
+    )?;
+    for (name, value) in response.headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    let body = response.body.unwrap_or_default();
+    if !body.is_empty() {
+        write!(stream, "Content-Type: {}\r\n", response.content_type)?;
+    }
+    write!(stream, "Content-Length: {}\r\n", body.len())?;
+    write!(stream, "Connection: close\r\n\r\n{}", body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Decode `%XX` escapes in a URL path segment.
+fn percent_decode(input: &str) -> Option<String> {
+    let bytes = input.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = input.get(i + 1..i + 3)?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Extract the user name and password of a `Basic` authorization header.
+fn basic_credentials(header: &str) -> Option<(String, String)> {
+    let encoded = header.strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(base64_decode(encoded.trim())?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+fn base64_decode(input: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(input.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+    for c in input.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+    Some(decoded)
+}
@@ -4,20 +4,55 @@
 //! `kvs` is a simple in-memory key/value store that maps strings
 //! to strings.
 #[macro_use]
-extern crate failure;
-#[macro_use]
 extern crate log;
+mod async_client;
+mod async_server;
+mod auth;
 mod client;
 mod engine;
 mod error;
+mod event_loop;
+mod http;
+mod limits;
+mod lock;
+mod metrics;
+mod migration;
 mod network;
+mod pool;
+mod proxy;
+mod raft;
+mod replication;
+mod retry;
 mod server;
+mod shard;
+mod shutdown;
+mod slowlog;
+mod stats;
 pub mod thread_pool;
+mod tls;
+mod transport;
 
-pub use crate::client::KvsClient;
+pub use crate::async_client::AsyncKvsClient;
+pub use crate::async_server::AsyncKvsServer;
+pub use crate::auth::{Access, Acl};
+pub use crate::client::{ClientOptions, KvsClient};
+pub use crate::engine::boxed::*;
+pub use crate::engine::convert::*;
+pub use crate::engine::registry::*;
 pub use crate::engine::simple_kvs::*;
 pub use crate::engine::sled_kvs::*;
 pub use crate::engine::*;
 pub use crate::error::KvsError;
+pub use crate::limits::parse_seconds;
+pub use crate::lock::DirLock;
 // pub use crate::network::{Request, Response};
+pub use crate::pool::{PoolOptions, PooledKvsClient};
+pub use crate::proxy::{KvsProxy, ProxyOptions};
+pub use crate::raft::ClusterConfig;
+pub use crate::retry::RetryPolicy;
 pub use crate::server::KvsServer;
+pub use crate::shard::ShardedKvsClient;
+pub use crate::shutdown::ShutdownHandle;
+pub use crate::slowlog::SlowQuery;
+pub use crate::tls::{TlsClientConfig, TlsServerConfig};
+pub use crate::transport::KvsAddr;
@@ -0,0 +1,50 @@
+use crate::error::KvsError;
+use std::io;
+use std::time::Duration;
+
+/// How long input is still read and discarded when closing a connection after
+/// an error response, so that the client gets the response rather than a reset.
+pub(crate) const LINGER: Duration = Duration::from_secs(1);
+
+/// The largest request a server reads, in bytes.
+pub(crate) const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Parse a positive number of seconds, possibly fractional, as given on a command line.
+pub fn parse_seconds(s: &str) -> std::result::Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs.is_finite() && secs > 0.0 => {
+            Duration::try_from_secs_f64(secs).map_err(|err| err.to_string())
+        }
+        _ => Err(format!("invalid number of seconds: {}", s)),
+    }
+}
+
+/// The timeouts and caps applied to the client connections of a server.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Limits {
+    /// The longest wait for the next bytes of a request being received.
+    pub(crate) read_timeout: Option<Duration>,
+    /// The longest wait for the client to accept the bytes of a response.
+    pub(crate) write_timeout: Option<Duration>,
+    /// The longest wait for the first byte of the next request.
+    pub(crate) idle_timeout: Option<Duration>,
+    /// The most client connections open at once.
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl Limits {
+    pub(crate) fn is_full(&self, connections: usize) -> bool {
+        self.max_connections.is_some_and(|max| connections >= max)
+    }
+}
+
+/// Whether `err` is a socket timing out.
+pub(crate) fn is_timeout(err: &KvsError) -> bool {
+    match err {
+        KvsError::IoError(err) => matches!(
+            err.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+        ),
+        _ => false,
+    }
+}
@@ -0,0 +1,67 @@
+use crate::engine::Result;
+use crate::error::KvsError;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// An exclusive lock on a directory, held until dropped.
+///
+/// It is a lock on a file of the directory, which the OS releases when the
+/// process exits, even if it crashes.
+///
+/// # Examples
+/// ```rust
+/// # use kvs::{DirLock, KvsError};
+/// # use tempfile::TempDir;
+/// let temp_dir = TempDir::new().unwrap();
+/// let lock = DirLock::acquire(temp_dir.path(), "server.lock").unwrap();
+/// assert!(matches!(
+///     DirLock::acquire(temp_dir.path(), "server.lock"),
+///     Err(KvsError::DirectoryLocked(_))
+/// ));
+/// drop(lock);
+/// assert!(DirLock::acquire(temp_dir.path(), "server.lock").is_ok());
+/// ```
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// Lock `dir` by locking its file `name`, failing with
+    /// [`DirectoryLocked`](KvsError::DirectoryLocked) if it is locked already.
+    pub fn acquire(dir: &Path, name: &str) -> Result<DirLock> {
+        let path = dir.join(name);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        if let Err(err) = file.try_lock_exclusive() {
+            return Err(if is_contended(&err) {
+                KvsError::DirectoryLocked(PathBuf::from(dir))
+            } else {
+                err.into()
+            });
+        }
+        // The pid only helps to find the holder, the lock is what counts.
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(DirLock { file })
+    }
+}
+
+fn is_contended(err: &std::io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock
+        || err.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        if let Err(err) = self.file.unlock() {
+            warn!("failed to unlock a directory: {}", err);
+        }
+    }
+}
@@ -0,0 +1,235 @@
+//! Counters of the whole process, served in the Prometheus text format with
+//! [`KvsServer::with_metrics_addr`](crate::KvsServer::with_metrics_addr).
+use crate::network::COMMANDS;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::time::Duration;
+
+/// The upper bounds of the latency buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0,
+];
+
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+pub(crate) struct Metrics {
+    /// The requests of each command, indexed as [`COMMANDS`].
+    commands: [Command; COMMANDS.len()],
+    connections: AtomicI64,
+    queued_jobs: AtomicI64,
+    /// The share of stale entries in the log of the last written `KvStore`, as `f64` bits.
+    garbage_ratio: AtomicU64,
+    compactions: Histogram,
+    compactions_started: AtomicU64,
+    compactions_finished: AtomicU64,
+}
+
+struct Command {
+    errors: AtomicU64,
+    latency: Histogram,
+}
+
+impl Command {
+    const fn new() -> Self {
+        Command {
+            errors: AtomicU64::new(0),
+            latency: Histogram::new(),
+        }
+    }
+}
+
+struct Histogram {
+    /// How many observations fell in each bucket, the last one being `+Inf`.
+    buckets: [AtomicU64; BUCKETS.len() + 1],
+    /// The sum of the observations, in nanoseconds.
+    sum: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len() + 1],
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let nanos = u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        self.sum.fetch_add(nanos, Ordering::Relaxed);
+    }
+
+    fn count(&self) -> u64 {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Write the `_bucket`, `_sum` and `_count` series of `name`, with `labels`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, count) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        // The count is taken from the same loads as the buckets, so that they agree.
+        let count = cumulative + self.buckets[BUCKETS.len()].load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{}le=\"+Inf\"}} {}", name, labels, count);
+        let labels = labels.trim_end_matches(',');
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let sum = self.sum.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{}_sum{} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{} {}", name, labels, count);
+    }
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            commands: [const { Command::new() }; COMMANDS.len()],
+            connections: AtomicI64::new(0),
+            queued_jobs: AtomicI64::new(0),
+            garbage_ratio: AtomicU64::new(0),
+            compactions: Histogram::new(),
+            compactions_started: AtomicU64::new(0),
+            compactions_finished: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a request for the command `command` indexes, that took `duration`,
+    /// and failed unless `ok`.
+    pub(crate) fn request(&self, command: usize, duration: Duration, ok: bool) {
+        let command = &self.commands[command];
+        command.latency.observe(duration);
+        if !ok {
+            command.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Count `job` as queued until a thread of the pool starts running it.
+    pub(crate) fn queue<F: FnOnce()>(&'static self, job: F) -> impl FnOnce() {
+        self.queued_jobs.fetch_add(1, Ordering::Relaxed);
+        move || {
+            self.queued_jobs.fetch_sub(1, Ordering::Relaxed);
+            job()
+        }
+    }
+
+    /// Record how many of the `entries` of a log are stale.
+    pub(crate) fn garbage(&self, stale: u64, entries: u64) {
+        let ratio = if entries == 0 {
+            0.0
+        } else {
+            stale as f64 / entries as f64
+        };
+        self.garbage_ratio.store(ratio.to_bits(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn compaction_started(&self) {
+        self.compactions_started.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn compaction_finished(&self, duration: Duration) {
+        self.compactions.observe(duration);
+        self.compactions_finished.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// A mark to tell with [`compacted_since`](Metrics::compacted_since) whether
+    /// a compaction ran after it was taken.
+    pub(crate) fn compaction_mark(&self) -> u64 {
+        self.compactions_finished.load(Ordering::SeqCst)
+    }
+
+    /// Whether a compaction ran, even partly, since `mark` was taken.
+    pub(crate) fn compacted_since(&self, mark: u64) -> bool {
+        self.compactions_started.load(Ordering::SeqCst) > mark
+    }
+
+    /// The metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        // Only the commands requested so far are listed.
+        let commands: Vec<(&str, &Command)> = COMMANDS
+            .iter()
+            .copied()
+            .zip(self.commands.iter())
+            .filter(|(_, command)| command.latency.count() > 0)
+            .collect();
+
+        out.push_str("# HELP kvs_requests_total Requests served, by command.\n");
+        out.push_str("# TYPE kvs_requests_total counter\n");
+        for (name, command) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_requests_total{{command=\"{}\"}} {}",
+                name,
+                command.latency.count()
+            );
+        }
+        out.push_str("# HELP kvs_request_errors_total Requests that failed, by command.\n");
+        out.push_str("# TYPE kvs_request_errors_total counter\n");
+        for (name, command) in commands.iter() {
+            let _ = writeln!(
+                out,
+                "kvs_request_errors_total{{command=\"{}\"}} {}",
+                name,
+                command.errors.load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# HELP kvs_request_duration_seconds Time to run a request, by command.\n");
+        out.push_str("# TYPE kvs_request_duration_seconds histogram\n");
+        for (name, command) in commands.iter() {
+            let labels = format!("command=\"{}\",", name);
+            command
+                .latency
+                .render(&mut out, "kvs_request_duration_seconds", &labels);
+        }
+
+        out.push_str("# HELP kvs_open_connections Client connections currently open.\n");
+        out.push_str("# TYPE kvs_open_connections gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_open_connections {}",
+            self.connections.load(Ordering::Relaxed)
+        );
+        out.push_str("# HELP kvs_thread_pool_queue_depth Jobs waiting for a thread of a pool.\n");
+        out.push_str("# TYPE kvs_thread_pool_queue_depth gauge\n");
+        let _ = writeln!(
+            out,
+            "kvs_thread_pool_queue_depth {}",
+            self.queued_jobs.load(Ordering::Relaxed)
+        );
+        out.push_str("# HELP kvs_garbage_ratio Share of stale entries in the log of the store.\n");
+        out.push_str("# TYPE kvs_garbage_ratio gauge\n");
+        let ratio = f64::from_bits(self.garbage_ratio.load(Ordering::Relaxed));
+        let _ = writeln!(out, "kvs_garbage_ratio {}", ratio);
+        out.push_str("# HELP kvs_compaction_duration_seconds Time to compact the log.\n");
+        out.push_str("# TYPE kvs_compaction_duration_seconds histogram\n");
+        self.compactions
+            .render(&mut out, "kvs_compaction_duration_seconds", "");
+        out
+    }
+}
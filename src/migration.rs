@@ -0,0 +1,503 @@
+//! Moving a range of keys to another server while both stay live.
+//!
+//! The server wraps its engine in [`Migrating`]. An admin sends
+//! `Request::Migrate` to the server owning the keys, which copies them to the
+//! target server in batches. Meanwhile the writes to the range are applied
+//! locally and forwarded to the target, so that it does not miss any. Once the
+//! copy completes, the range changes hands under the same lock as the writes:
+//! from then on its keys are redirected to the target, and the local copies are
+//! removed.
+//!
+//! The moved ranges are stored in a file before the local copies are removed,
+//! so that the server still redirects them after a restart. Clients are expected
+//! to route the range to the target once the migration is done.
+use crate::client::{ClientOptions, KvsClient};
+use crate::engine::{KvsEngine, Result};
+use crate::error::KvsError;
+use crate::transport::KvsAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+
+/// How many keys are copied to the target at once.
+const BATCH_SIZE: usize = 100;
+/// How long to wait for the target when the migration options set no timeout.
+const TARGET_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The keys from `start` included to `end` excluded, in lexicographic order,
+/// or every key from `start` on if there is no end.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyRange {
+    pub(crate) start: String,
+    pub(crate) end: Option<String>,
+}
+
+impl KeyRange {
+    fn contains(&self, key: &str) -> bool {
+        self.start.as_str() <= key && self.end.as_ref().is_none_or(|end| key < end.as_str())
+    }
+}
+
+impl fmt::Display for KeyRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.end {
+            Some(ref end) => write!(f, "[{:?}, {:?})", self.start, end),
+            None => write!(f, "[{:?}, ..)", self.start),
+        }
+    }
+}
+
+/// An engine whose ranges of keys can be moved to other servers.
+#[derive(Clone)]
+pub(crate) struct Migrating<E: KvsEngine> {
+    engine: E,
+    state: Arc<RwLock<State>>,
+    /// The connection to the target of the migration in progress, locked while
+    /// sending to it, so that the copy and the forwarded writes arrive in order.
+    target: Arc<Mutex<Option<KvsClient>>>,
+    options: ClientOptions,
+    /// The address the server listens to, which cannot be a target.
+    local: KvsAddr,
+    /// The file the moved ranges are stored in, without which nothing can be moved.
+    file: Option<Arc<PathBuf>>,
+}
+
+/// A moved range, as stored in the file of the moved ranges.
+#[derive(Deserialize, Serialize)]
+struct MovedRange {
+    start: String,
+    end: Option<String>,
+    target: String,
+}
+
+/// Read the moved ranges stored in `path`, if any.
+fn load_moved(path: &Path) -> Result<Vec<(KeyRange, KvsAddr)>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+    let ranges: Vec<MovedRange> = serde_json::from_reader(BufReader::new(file))?;
+    ranges
+        .into_iter()
+        .map(|moved| {
+            let range = KeyRange {
+                start: moved.start,
+                end: moved.end,
+            };
+            Ok((range, moved.target.parse()?))
+        })
+        .collect()
+}
+
+/// Replace the moved ranges stored in `path` with `moved`, durably.
+fn save_moved(path: &Path, moved: &[(KeyRange, KvsAddr)]) -> Result<()> {
+    let ranges: Vec<MovedRange> = moved
+        .iter()
+        .map(|(range, target)| MovedRange {
+            start: range.start.clone(),
+            end: range.end.clone(),
+            target: target.to_string(),
+        })
+        .collect();
+    let tmp = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp)?);
+    serde_json::to_writer(&mut writer, &ranges)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+struct State {
+    moving: Option<Moving>,
+    /// The ranges moved to other servers.
+    moved: Vec<(KeyRange, KvsAddr)>,
+}
+
+/// A migration in progress.
+struct Moving {
+    range: KeyRange,
+    /// The keys written since the migration started, which the target gets from
+    /// `queue` rather than from the copy.
+    written: HashSet<String>,
+    /// The writes to the range applied locally but not yet sent to the target.
+    queue: VecDeque<Forward>,
+    /// Why forwarding a write to the target failed, aborting the migration.
+    failed: Option<String>,
+}
+
+/// A write to forward to the target of a migration.
+enum Forward {
+    Set(Vec<(String, String)>),
+    Remove(Vec<String>),
+}
+
+impl Forward {
+    fn send(self, target: &mut KvsClient) -> Result<()> {
+        match self {
+            Forward::Set(pairs) => target.mset(pairs),
+            Forward::Remove(keys) => target.mdel(keys).map(|_| ()),
+        }
+    }
+}
+
+impl State {
+    /// Check this server still owns `keys`.
+    fn check_owned<'a>(&self, keys: impl IntoIterator<Item = &'a String>) -> Result<()> {
+        let mut targets = Vec::new();
+        let mut owned = false;
+        for key in keys {
+            match self.moved.iter().find(|(range, _)| range.contains(key)) {
+                Some((_, target)) if !targets.contains(&target) => targets.push(target),
+                Some(_) => {}
+                None => owned = true,
+            }
+        }
+        match (targets.as_slice(), owned) {
+            ([], _) => Ok(()),
+            ([target], false) => Err(KvsError::Redirect(target.to_string())),
+            _ => Err(KvsError::InvalidRequest(
+                "The keys are on different servers".to_owned(),
+            )),
+        }
+    }
+
+    /// The migration in progress, unless it failed.
+    fn moving(&mut self) -> Option<&mut Moving> {
+        self.moving
+            .as_mut()
+            .filter(|moving| moving.failed.is_none())
+    }
+}
+
+impl<E: KvsEngine> Migrating<E> {
+    /// Wrap `engine` of the server listening to `local`, connecting to the
+    /// targets of migrations with `options`, and storing the moved ranges in `file`.
+    pub(crate) fn new(
+        engine: E,
+        mut options: ClientOptions,
+        local: KvsAddr,
+        file: Option<PathBuf>,
+    ) -> Result<Self> {
+        options.read_timeout.get_or_insert(TARGET_TIMEOUT);
+        options.write_timeout.get_or_insert(TARGET_TIMEOUT);
+        let moved = match file {
+            Some(ref file) => load_moved(file)?,
+            None => Vec::new(),
+        };
+        Ok(Migrating {
+            engine,
+            state: Arc::new(RwLock::new(State {
+                moving: None,
+                moved,
+            })),
+            target: Arc::new(Mutex::new(None)),
+            options,
+            local,
+            file: file.map(Arc::new),
+        })
+    }
+
+    pub(crate) fn engine(&self) -> &E {
+        &self.engine
+    }
+
+    fn read(&self) -> Result<RwLockReadGuard<'_, State>> {
+        Ok(self.state.read().map_err(|err| err.to_string())?)
+    }
+
+    fn write_lock(&self) -> Result<RwLockWriteGuard<'_, State>> {
+        Ok(self.state.write().map_err(|err| err.to_string())?)
+    }
+
+    fn target(&self) -> Result<MutexGuard<'_, Option<KvsClient>>> {
+        Ok(self.target.lock().map_err(|err| err.to_string())?)
+    }
+
+    /// Move the keys of `range` to the server on `target`, returning how many were copied.
+    pub(crate) fn migrate(&self, range: KeyRange, target: KvsAddr) -> Result<usize> {
+        let file = match self.file {
+            Some(ref file) => file.clone(),
+            // A restart would forget the range is moved, and serve it again.
+            None => {
+                return Err(KvsError::InvalidRequest(
+                    "The server has no file to store the moved ranges in".to_owned(),
+                ))
+            }
+        };
+        if is_same_server(&target, &self.local) {
+            return Err(KvsError::InvalidRequest(format!(
+                "Cannot migrate to the server itself on {}",
+                target
+            )));
+        }
+        let client = KvsClient::connect(&target, &self.options)?;
+        {
+            let mut state = self.write_lock()?;
+            if state.moving.is_some() {
+                return Err(KvsError::ServerBusy(
+                    "A migration is already running".to_owned(),
+                ));
+            }
+            state.moving = Some(Moving {
+                range: range.clone(),
+                written: HashSet::new(),
+                queue: VecDeque::new(),
+                failed: None,
+            });
+            *self.target()? = Some(client);
+        }
+        info!("migrating {} to {}", range, target);
+        let result = self.copy(&range).and_then(|copied| {
+            self.hand_over(&range, &target, &file)?;
+            Ok(copied)
+        });
+        *self.target()? = None;
+        let copied = match result {
+            Ok(copied) => copied,
+            Err(err) => {
+                self.write_lock()?.moving = None;
+                return Err(err);
+            }
+        };
+        info!("{} is now owned by {}", range, target);
+
+        let keys = self.keys(&range)?;
+        self.engine.remove_many(keys)?;
+        Ok(copied)
+    }
+
+    /// Copy the keys of `range` to the target of the migration in progress.
+    fn copy(&self, range: &KeyRange) -> Result<usize> {
+        let mut copied = 0;
+        for batch in self.keys(range)?.chunks(BATCH_SIZE) {
+            let values = self.engine.get_many(batch.to_vec())?;
+            let mut target = self.target()?;
+            let pairs: Vec<_> = {
+                let mut state = self.write_lock()?;
+                let moving = match state.moving() {
+                    Some(moving) => moving,
+                    None => break,
+                };
+                // The keys written since they were read have a newer value on the way.
+                batch
+                    .iter()
+                    .zip(values)
+                    .filter(|(key, _)| !moving.written.contains(*key))
+                    .filter_map(|(key, value)| Some((key.clone(), value?)))
+                    .collect()
+            };
+            copied += pairs.len();
+            target.as_mut().unwrap().mset(pairs)?;
+        }
+        Ok(copied)
+    }
+
+    /// Once the copy is complete, send the forwarded writes left, and redirect
+    /// `range` to `target` as soon as none is left, storing it in `file` first.
+    fn hand_over(&self, range: &KeyRange, target: &KvsAddr, file: &Path) -> Result<()> {
+        loop {
+            self.send_queued()?;
+            let mut state = self.write_lock()?;
+            let moving = state.moving.take().unwrap();
+            if let Some(failed) = moving.failed {
+                return Err(KvsError::ServerError(failed));
+            }
+            if moving.queue.is_empty() {
+                let mut moved = state.moved.clone();
+                moved.push((range.clone(), target.clone()));
+                save_moved(file, &moved)?;
+                state.moved = moved;
+                return Ok(());
+            }
+            // Writes were queued meanwhile.
+            state.moving = Some(moving);
+        }
+    }
+
+    /// Send the queued writes to the target of the migration in progress, in
+    /// order, or abort the migration if one fails.
+    fn send_queued(&self) -> Result<()> {
+        let mut target = self.target()?;
+        let target = match target.as_mut() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        loop {
+            let forward = match self.write_lock()?.moving() {
+                Some(moving) => moving.queue.pop_front(),
+                None => None,
+            };
+            let forward = match forward {
+                Some(forward) => forward,
+                None => return Ok(()),
+            };
+            if let Err(err) = forward.send(target) {
+                if let Some(moving) = self.write_lock()?.moving() {
+                    warn!("aborting the migration of {}: {}", moving.range, err);
+                    moving.failed = Some(err.to_string());
+                }
+                return Ok(());
+            }
+        }
+    }
+
+    /// The keys of `range`.
+    fn keys(&self, range: &KeyRange) -> Result<Vec<String>> {
+        let mut keys = Vec::new();
+        self.engine.scan(&mut |key, _| {
+            if range.contains(&key) {
+                keys.push(key);
+            }
+            Ok(())
+        })?;
+        Ok(keys)
+    }
+
+    /// Run `write` on the engine, and queue it for the target too for the keys
+    /// being moved.
+    ///
+    /// While a migration runs, both happen under the same lock, so that the copy
+    /// never overwrites the target with an older value, and no write is missed
+    /// when the range changes hands. The queue is sent outside the lock.
+    fn write<T>(
+        &self,
+        keys: &[&String],
+        local: impl FnOnce(&E) -> Result<T>,
+        forward: impl FnOnce(&dyn Fn(&str) -> bool) -> Forward,
+    ) -> Result<T> {
+        {
+            let state = self.read()?;
+            if state.moving.is_none() {
+                state.check_owned(keys.iter().copied())?;
+                return local(&self.engine);
+            }
+        }
+
+        let result = {
+            let mut state = self.write_lock()?;
+            state.check_owned(keys.iter().copied())?;
+            let result = local(&self.engine)?;
+            if let Some(moving) = state.moving() {
+                let range = moving.range.clone();
+                let moved: Vec<&String> = keys
+                    .iter()
+                    .copied()
+                    .filter(|key| range.contains(key))
+                    .collect();
+                if !moved.is_empty() {
+                    moving.written.extend(moved.into_iter().cloned());
+                    moving
+                        .queue
+                        .push_back(forward(&|key: &str| range.contains(key)));
+                }
+            }
+            result
+        };
+        self.send_queued()?;
+        Ok(result)
+    }
+}
+
+/// Whether `target` is the address of the server listening to `local`.
+fn is_same_server(target: &KvsAddr, local: &KvsAddr) -> bool {
+    match (target, local) {
+        (KvsAddr::Tcp(target), KvsAddr::Tcp(local)) => {
+            target.port() == local.port()
+                && (target.ip() == local.ip()
+                    || local.ip().is_unspecified()
+                    || target.ip().is_loopback() && local.ip().is_loopback())
+        }
+        _ => target == local,
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Migrating<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let forwarded = (key.clone(), value.clone());
+        self.write(
+            &[&key],
+            |engine| engine.set(key.clone(), value),
+            |_| Forward::Set(vec![forwarded]),
+        )
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read()?.check_owned(std::slice::from_ref(&key))?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let forwarded = key.clone();
+        self.write(
+            &[&key],
+            |engine| engine.remove(key.clone()),
+            |_| Forward::Remove(vec![forwarded]),
+        )
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.engine.scan(f)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.read()?.check_owned(&keys)?;
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let forwarded = pairs.clone();
+        let keys: Vec<&String> = forwarded.iter().map(|(key, _)| key).collect();
+        self.write(
+            &keys,
+            |engine| engine.set_many(pairs),
+            |moved| {
+                let pairs = forwarded
+                    .iter()
+                    .filter(|(key, _)| moved(key))
+                    .cloned()
+                    .collect();
+                Forward::Set(pairs)
+            },
+        )
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        let forwarded = keys.clone();
+        let refs: Vec<&String> = forwarded.iter().collect();
+        self.write(
+            &refs,
+            |engine| engine.remove_many(keys),
+            |moved| {
+                let keys = forwarded.iter().filter(|key| moved(key)).cloned().collect();
+                Forward::Remove(keys)
+            },
+        )
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        self.engine.key_count()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.engine.disk_size()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
@@ -1,7 +1,15 @@
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::slowlog::SlowQuery;
+use crate::transport::with_timeout;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 /// Network protocol of kvs-client and kvs-server
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Request {
     /// Set the value of a string key to a string
     Set { key: String, value: String },
@@ -9,10 +17,405 @@ pub enum Request {
     Get { key: String },
     /// Remove a given string key
     Remove { key: String },
+    /// Authenticate the connection as `user`
+    Auth { user: String, token: String },
+    /// Get the values of many keys, answered with `Response::Values` in the same order
+    MGet { keys: Vec<String> },
+    /// Set the values of many keys
+    MSet { pairs: Vec<(String, String)> },
+    /// Remove many keys, answered with `Response::Values` holding each removed key,
+    /// or None for the keys that did not exist
+    MDel { keys: Vec<String> },
+    /// Follow the writes of the server: answered with `Response::Ok`, followed by
+    /// a stream of replicated writes
+    Replicate,
+    /// Talk Raft with a node of the cluster: answered with `Response::Ok`, followed
+    /// by Raft messages in both directions
+    Raft,
+    /// Move the keys from `start` to `end` excluded, or to the last one without an
+    /// end, to the server on `target`, answered with the number of keys copied
+    Migrate {
+        start: String,
+        end: Option<String>,
+        target: String,
+    },
+    /// Describe the engine: its name, number of keys and size on the disk
+    Info,
+    /// Report the uptime of the server and how many requests it served
+    Stats,
+    /// Compact the data of the engine now
+    Compact,
+    /// Check the server is alive, answered with `PONG`
+    Ping,
+    /// Remove every key
+    FlushAll,
+    /// Get the last requests slower than the threshold of the server, answered
+    /// with `Response::SlowLog`
+    SlowLog,
 }
 
+impl Request {
+    /// Whether running the request twice has the same effect as running it once.
+    pub(crate) fn is_idempotent(&self) -> bool {
+        match self {
+            Request::Set { .. }
+            | Request::Get { .. }
+            | Request::Auth { .. }
+            | Request::MGet { .. }
+            | Request::MSet { .. }
+            | Request::Info
+            | Request::Stats
+            | Request::Ping
+            | Request::SlowLog => true,
+            // Run again, they would wipe or rewrite the writes made since the first run.
+            Request::Compact | Request::FlushAll => false,
+            Request::Remove { .. }
+            | Request::MDel { .. }
+            | Request::Replicate
+            | Request::Raft
+            | Request::Migrate { .. } => false,
+        }
+    }
+
+    /// The command, as counted by the server: its index in [`COMMANDS`].
+    pub(crate) fn command(&self) -> usize {
+        match self {
+            Request::Auth { .. } => 0,
+            Request::Compact => 1,
+            Request::FlushAll => 2,
+            Request::Get { .. } => 3,
+            Request::Info => 4,
+            Request::MDel { .. } => 5,
+            Request::MGet { .. } => 6,
+            Request::Migrate { .. } => 7,
+            Request::MSet { .. } => 8,
+            Request::Ping => 9,
+            Request::Raft => 10,
+            Request::Remove { .. } => 11,
+            Request::Replicate => 12,
+            Request::Set { .. } => 13,
+            Request::SlowLog => 14,
+            Request::Stats => 15,
+        }
+    }
+}
+
+/// The names of the commands, in alphabetical order.
+pub(crate) const COMMANDS: [&str; 16] = [
+    "auth",
+    "compact",
+    "flushall",
+    "get",
+    "info",
+    "mdel",
+    "mget",
+    "migrate",
+    "mset",
+    "ping",
+    "raft",
+    "remove",
+    "replicate",
+    "set",
+    "slowlog",
+    "stats",
+];
+
 #[derive(Debug, Deserialize, Serialize)]
 pub enum Response {
     Ok(Option<String>),
-    Err(String),
+    /// The results of a multi-key request, one per key, in request order
+    Values(Vec<Option<String>>),
+    /// The slow requests of the server, oldest first
+    SlowLog(Vec<SlowQuery>),
+    Err(ErrorCode, String),
+}
+
+impl Response {
+    /// The value of a single-key response, turning an error response into the matching `KvsError`.
+    pub(crate) fn into_value(self) -> Result<Option<String>> {
+        match self {
+            Response::Ok(value) => Ok(value),
+            Response::Err(code, message) => Err(code.into_error(message)),
+            Response::Values(_) | Response::SlowLog(_) => Err(unexpected(self)),
+        }
+    }
+
+    /// The values of a multi-key response, turning an error response into the matching `KvsError`.
+    pub(crate) fn into_values(self) -> Result<Vec<Option<String>>> {
+        match self {
+            Response::Values(values) => Ok(values),
+            Response::Err(code, message) => Err(code.into_error(message)),
+            Response::Ok(_) | Response::SlowLog(_) => Err(unexpected(self)),
+        }
+    }
+
+    /// The requests of a slow-query log response, turning an error response into the matching `KvsError`.
+    pub(crate) fn into_slowlog(self) -> Result<Vec<SlowQuery>> {
+        match self {
+            Response::SlowLog(queries) => Ok(queries),
+            Response::Err(code, message) => Err(code.into_error(message)),
+            Response::Ok(_) | Response::Values(_) => Err(unexpected(self)),
+        }
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::StringError(format!("unexpected response: {:?}", response))
+}
+
+impl From<KvsError> for Response {
+    fn from(err: KvsError) -> Response {
+        match err {
+            // Clients need the bare address to follow the redirect.
+            KvsError::Redirect(addr) => Response::Err(ErrorCode::Redirect, addr),
+            err => Response::Err(ErrorCode::from(&err), err.to_string()),
+        }
+    }
+}
+
+/// Why a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum ErrorCode {
+    /// The key does not exist
+    NotFound,
+    /// The request could not be understood
+    InvalidRequest,
+    /// The engine failed to read or write its data
+    StorageError,
+    /// The connection is not authenticated, or not allowed to run the request
+    Unauthorized,
+    /// The server is overloaded, the request may be retried later
+    Busy,
+    /// Any other failure of the server
+    Internal,
+    /// The server does not take writes, the message is the address of the one that does
+    Redirect,
+}
+
+impl From<&KvsError> for ErrorCode {
+    fn from(err: &KvsError) -> ErrorCode {
+        match err {
+            KvsError::KeyNotFound => ErrorCode::NotFound,
+            KvsError::SerdeJsonError(_) | KvsError::InvalidRequest(_) => ErrorCode::InvalidRequest,
+            KvsError::IoError(_)
+            | KvsError::BsonSerError(_)
+            | KvsError::BsonDeError(_)
+            | KvsError::SledError(_)
+            | KvsError::FromUtf8Error(_)
+            | KvsError::NotValidLog
+            | KvsError::StorageError(_) => ErrorCode::StorageError,
+            KvsError::Unauthorized(_) => ErrorCode::Unauthorized,
+            KvsError::ServerBusy(_) => ErrorCode::Busy,
+            KvsError::Redirect(_) => ErrorCode::Redirect,
+            KvsError::TlsError(_)
+            | KvsError::StringError(_)
+            | KvsError::MismatchEngine
+            | KvsError::DirectoryLocked(_)
+            | KvsError::ServerError(_) => ErrorCode::Internal,
+        }
+    }
+}
+
+impl ErrorCode {
+    /// Turn an error response back into the matching `KvsError`.
+    pub fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorCode::NotFound => KvsError::KeyNotFound,
+            ErrorCode::InvalidRequest => KvsError::InvalidRequest(message),
+            ErrorCode::StorageError => KvsError::StorageError(message),
+            ErrorCode::Unauthorized => KvsError::Unauthorized(message),
+            ErrorCode::Busy => KvsError::ServerBusy(message),
+            ErrorCode::Internal => KvsError::ServerError(message),
+            ErrorCode::Redirect => KvsError::Redirect(message),
+        }
+    }
+}
+
+/// The bytes read from a connection, holding the messages not taken out yet.
+///
+/// Messages are not framed, so the bytes are scanned for the end of the first
+/// message as they come in, and each byte is only scanned once.
+#[derive(Debug, Default)]
+pub(crate) struct MessageBuf {
+    bytes: Vec<u8>,
+    max_size: Option<usize>,
+    /// How many bytes were scanned for the end of the first message.
+    scanned: usize,
+    /// Whether the first message started in the scanned bytes.
+    started: bool,
+    /// How deep in objects and arrays the scanned bytes end.
+    depth: usize,
+    /// Whether the scanned bytes end in a string.
+    in_string: bool,
+    /// Whether the scanned bytes end with a backslash in a string.
+    escaped: bool,
+}
+
+impl MessageBuf {
+    /// An empty buffer, taking messages of any size.
+    pub(crate) fn new() -> MessageBuf {
+        MessageBuf::default()
+    }
+
+    /// Fail the connection once a message being received is larger than `max_size` bytes.
+    pub(crate) fn with_max_size(mut self, max_size: usize) -> MessageBuf {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Append bytes read from the connection.
+    pub(crate) fn extend(&mut self, bytes: &[u8]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /// Drop the bytes held, and the messages in them.
+    pub(crate) fn clear(&mut self) {
+        self.bytes.clear();
+        self.reset();
+    }
+
+    /// How many bytes the buffer holds.
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /// Whether the buffer holds nothing but whitespace.
+    pub(crate) fn is_blank(&self) -> bool {
+        !self.started
+            && self.bytes[self.scanned..]
+                .iter()
+                .all(u8::is_ascii_whitespace)
+    }
+
+    /// Take the first message out, or `None` if the buffer does not hold a whole one yet.
+    pub(crate) fn decode<T: DeserializeOwned>(&mut self) -> Result<Option<T>> {
+        let end = self.message_end();
+        if let Some(max_size) = self.max_size {
+            if end.unwrap_or(self.bytes.len()) > max_size {
+                return Err(KvsError::InvalidRequest(format!(
+                    "message larger than {} bytes",
+                    max_size
+                )));
+            }
+        }
+        let end = match end {
+            Some(end) => end,
+            None => return Ok(None),
+        };
+        let mut messages =
+            serde_json::Deserializer::from_slice(&self.bytes[..end]).into_iter::<T>();
+        let (message, len) = match messages.next() {
+            Some(Ok(message)) => (message, messages.byte_offset()),
+            Some(Err(err)) if err.is_eof() => return Ok(None),
+            Some(Err(err)) => return Err(err.into()),
+            None => return Ok(None),
+        };
+        self.bytes.drain(..len);
+        self.reset();
+        Ok(Some(message))
+    }
+
+    /// Scan the new bytes for the end of the first message, returning its offset if found.
+    ///
+    /// Messages are objects or strings; anything else is left to serde to make sense of.
+    fn message_end(&mut self) -> Option<usize> {
+        while self.scanned < self.bytes.len() {
+            let byte = self.bytes[self.scanned];
+            self.scanned += 1;
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false;
+                } else if byte == b'\\' {
+                    self.escaped = true;
+                } else if byte == b'"' {
+                    self.in_string = false;
+                    if self.depth == 0 {
+                        return Some(self.scanned);
+                    }
+                }
+                continue;
+            }
+            if byte.is_ascii_whitespace() {
+                continue;
+            }
+            self.started = true;
+            match byte {
+                b'"' => self.in_string = true,
+                b'{' | b'[' => self.depth += 1,
+                b'}' | b']' if self.depth > 0 => {
+                    self.depth -= 1;
+                    if self.depth == 0 {
+                        return Some(self.scanned);
+                    }
+                }
+                _ if self.depth == 0 => return Some(self.bytes.len()),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn reset(&mut self) {
+        self.scanned = 0;
+        self.started = false;
+        self.depth = 0;
+        self.in_string = false;
+        self.escaped = false;
+    }
+}
+
+/// Read the next message from an async stream, or `None` once the peer closed the connection.
+///
+/// The reads time out after `idle` while waiting for the first byte of the
+/// message, and after `read` while waiting for the next ones.
+///
+/// The bytes read past the message are kept in `buf` for the next call.
+/// It is cancel safe: nothing is lost if the future is dropped.
+pub(crate) async fn read_message<T, R>(
+    stream: &mut R,
+    buf: &mut MessageBuf,
+    idle: Option<Duration>,
+    read: Option<Duration>,
+) -> Result<Option<T>>
+where
+    T: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    loop {
+        if let Some(message) = buf.decode()? {
+            return Ok(Some(message));
+        }
+        let result = if buf.is_blank() {
+            with_timeout(idle, "idle", stream.read_buf(&mut buf.bytes)).await
+        } else {
+            with_timeout(read, "read", stream.read_buf(&mut buf.bytes)).await
+        };
+        match result {
+            Ok(0) if buf.is_blank() => return Ok(None),
+            Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+            Ok(_) => {}
+            // TLS peers may close the connection without a close_notify alert.
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Write a message to an async stream, giving up after `timeout`.
+pub(crate) async fn write_message<T, W>(
+    stream: &mut W,
+    message: &T,
+    timeout: Option<Duration>,
+) -> Result<()>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let bytes = serde_json::to_vec(message)?;
+    with_timeout(timeout, "write", async {
+        stream.write_all(&bytes).await?;
+        stream.flush().await
+    })
+    .await?;
+    Ok(())
 }
@@ -0,0 +1,261 @@
+use crate::client::{ClientOptions, KvsClient};
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::transport::KvsAddr;
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+const DEFAULT_MAX_SIZE: usize = 8;
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A client to speak to kvs server, keeping a pool of connections.
+///
+/// Unlike [`KvsClient`], it can be shared by many threads: it is cheap to clone,
+/// and all the clones share the same pool. Each request checks a connection out
+/// of the pool, connecting only when no idle connection is left.
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::{ClientOptions, PoolOptions, PooledKvsClient, Result};
+/// # use std::net::SocketAddr;
+/// # use std::thread;
+/// #
+/// # fn main() -> Result<()> {
+/// let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+/// let client = PooledKvsClient::new(addr, ClientOptions::default(), PoolOptions::default());
+///
+/// let handles: Vec<_> = (0..4)
+///     .map(|i| {
+///         let client = client.clone();
+///         thread::spawn(move || client.set(format!("key{}", i), "value".to_owned()))
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap()?;
+/// }
+/// assert_eq!(client.get("key0".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct PooledKvsClient {
+    pool: Arc<Pool>,
+}
+
+/// Options of the pool of a [`PooledKvsClient`].
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    max_size: usize,
+    checkout_timeout: Option<Duration>,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        PoolOptions {
+            max_size: DEFAULT_MAX_SIZE,
+            checkout_timeout: Some(DEFAULT_CHECKOUT_TIMEOUT),
+            idle_timeout: None,
+        }
+    }
+}
+
+impl PoolOptions {
+    /// Open at most `max` connections to the server. Defaults to 8.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is 0.
+    pub fn with_max_size(mut self, max: usize) -> Self {
+        assert!(max > 0, "a pool needs at least one connection");
+        self.max_size = max;
+        self
+    }
+
+    /// Give up waiting for a connection after `timeout` when they are all in use.
+    /// Defaults to 30 seconds, `None` to wait forever.
+    pub fn with_checkout_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.checkout_timeout = timeout;
+        self
+    }
+
+    /// Close the connections left idle for `timeout`, e.g. to stay below the
+    /// idle timeout of the server.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+}
+
+struct Pool {
+    addr: KvsAddr,
+    client: ClientOptions,
+    options: PoolOptions,
+    state: Mutex<State>,
+    released: Condvar,
+}
+
+struct State {
+    /// The connections ready for a request, the most recently used last.
+    idle: Vec<(KvsClient, Instant)>,
+    /// The number of connections, idle or checked out.
+    open: usize,
+}
+
+impl PooledKvsClient {
+    /// Create a client to `addr`. No connection is made until the first request.
+    pub fn new(addr: impl Into<KvsAddr>, client: ClientOptions, options: PoolOptions) -> Self {
+        let pool = Pool {
+            addr: addr.into(),
+            client,
+            options,
+            state: Mutex::new(State {
+                idle: Vec::new(),
+                open: 0,
+            }),
+            released: Condvar::new(),
+        };
+        PooledKvsClient {
+            pool: Arc::new(pool),
+        }
+    }
+
+    /// Send to the server to insert a key/value, and wait for the server to respond.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.pool.with_client(|client| client.set(key, value))
+    }
+
+    /// Send to the server to get the value match the key, and wait for the server to respond.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.pool.with_client(|client| client.get(key))
+    }
+
+    /// Send to the server to remove the given string key, and wait for the server to respond.
+    pub fn remove(&self, key: String) -> Result<Option<String>> {
+        self.pool.with_client(|client| client.remove(key))
+    }
+
+    /// Get the values of many keys in a single round trip, see [`KvsClient::mget`].
+    pub fn mget(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.pool.with_client(|client| client.mget(keys))
+    }
+
+    /// Insert many key/values in a single round trip, see [`KvsClient::mset`].
+    pub fn mset(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.pool.with_client(|client| client.mset(pairs))
+    }
+
+    /// Remove many keys in a single round trip, see [`KvsClient::mdel`].
+    pub fn mdel(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.pool.with_client(|client| client.mdel(keys))
+    }
+}
+
+impl Pool {
+    /// Run `f` on a connection checked out of the pool.
+    ///
+    /// The connection goes back to the pool afterwards, unless `f` failed in a
+    /// way that leaves it unusable.
+    fn with_client<T>(&self, f: impl FnOnce(&mut KvsClient) -> Result<T>) -> Result<T> {
+        let mut checked_out = CheckedOut {
+            pool: self,
+            client: Some(self.checkout()?),
+        };
+        let result = f(checked_out.client.as_mut().unwrap());
+        match result {
+            // Dropping it discards the connection.
+            Err(ref err) if is_broken(err) => {}
+            _ => checked_out.checkin(),
+        }
+        result
+    }
+
+    /// Take an idle connection that is still usable, or connect if the pool is not full,
+    /// or else wait for a connection to be released.
+    fn checkout(&self) -> Result<KvsClient> {
+        let deadline = self
+            .options
+            .checkout_timeout
+            .map(|timeout| Instant::now() + timeout);
+        let mut state = self.state.lock().unwrap();
+        loop {
+            while let Some((client, since)) = state.idle.pop() {
+                let expired = self
+                    .options
+                    .idle_timeout
+                    .is_some_and(|timeout| since.elapsed() >= timeout);
+                if !expired && client.is_reusable() {
+                    return Ok(client);
+                }
+                state.open -= 1;
+            }
+            if state.open < self.options.max_size {
+                state.open += 1;
+                drop(state);
+                return KvsClient::connect(&self.addr, &self.client)
+                    .inspect_err(|_| self.discard());
+            }
+            state = match deadline {
+                None => self.released.wait(state).unwrap(),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(KvsError::IoError(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "pool checkout timeout",
+                        )));
+                    }
+                    self.released.wait_timeout(state, deadline - now).unwrap().0
+                }
+            };
+        }
+    }
+
+    fn checkin(&self, client: KvsClient) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push((client, Instant::now()));
+        self.released.notify_one();
+    }
+
+    /// Forget a checked out connection, making room for a new one.
+    fn discard(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        self.released.notify_one();
+    }
+}
+
+/// A connection checked out of a pool, discarded when dropped without being
+/// checked in, e.g. when the request on it panics.
+struct CheckedOut<'a> {
+    pool: &'a Pool,
+    client: Option<KvsClient>,
+}
+
+impl CheckedOut<'_> {
+    fn checkin(mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.checkin(client);
+        }
+    }
+}
+
+impl Drop for CheckedOut<'_> {
+    fn drop(&mut self) {
+        if self.client.is_some() {
+            self.pool.discard();
+        }
+    }
+}
+
+/// Whether the connection a request failed on can no longer be used.
+///
+/// Error responses leave the connection usable, but failing to send a request
+/// or to read its response leaves it in an unknown state.
+pub(crate) fn is_broken(err: &KvsError) -> bool {
+    matches!(
+        err,
+        KvsError::IoError(_) | KvsError::SerdeJsonError(_) | KvsError::TlsError(_)
+    )
+}
@@ -0,0 +1,304 @@
+use crate::client::{ClientOptions, KvsClient};
+use crate::engine::{KvsEngine, Result};
+use crate::error::KvsError;
+use crate::pool::{is_broken, PoolOptions};
+use crate::shard::ShardedKvsClient;
+use crate::transport::KvsAddr;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_FAILURE_LIMIT: u32 = 3;
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// An engine forwarding every request to a set of backend kvs servers, picking
+/// the backend of a key with a consistent-hash ring, see [`ShardedKvsClient`].
+///
+/// Served by a [`KvsServer`](crate::KvsServer), it makes a proxy speaking the kvs
+/// protocol, so that clients need not know about the backends.
+///
+/// The backends are checked in the background by connecting to them. A backend
+/// failing too many health checks or requests in a row is ejected from the ring:
+/// its keys go to the next backends until it is healthy again. Their data is not
+/// copied over, so they are missing meanwhile.
+///
+/// Scanning the keys, and so replicating the proxy, is not supported.
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::thread_pool::{RayonThreadPool, ThreadPool};
+/// # use kvs::{ClientOptions, KvsAddr, KvsProxy, KvsServer, ProxyOptions, Result};
+/// #
+/// # fn main() -> Result<()> {
+/// let backends: Vec<KvsAddr> = vec!["127.0.0.1:4001".parse()?, "127.0.0.1:4002".parse()?];
+/// let proxy = KvsProxy::new(backends, ClientOptions::default(), ProxyOptions::default());
+///
+/// let server = KvsServer::new(proxy, RayonThreadPool::new(4)?, None).with_event_loop();
+/// server.run("127.0.0.1:4000".parse::<KvsAddr>()?)
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct KvsProxy {
+    inner: Arc<Inner>,
+}
+
+/// Options of a [`KvsProxy`].
+#[derive(Debug, Clone)]
+pub struct ProxyOptions {
+    pool: PoolOptions,
+    health_check_interval: Duration,
+    failure_limit: u32,
+}
+
+impl Default for ProxyOptions {
+    fn default() -> Self {
+        ProxyOptions {
+            pool: PoolOptions::default(),
+            health_check_interval: DEFAULT_HEALTH_CHECK_INTERVAL,
+            failure_limit: DEFAULT_FAILURE_LIMIT,
+        }
+    }
+}
+
+impl ProxyOptions {
+    /// Keep a pool of connections to each backend with `pool`.
+    pub fn with_pool(mut self, pool: PoolOptions) -> Self {
+        self.pool = pool;
+        self
+    }
+
+    /// Check the health of the backends every `interval`. Defaults to 1 second.
+    pub fn with_health_check_interval(mut self, interval: Duration) -> Self {
+        self.health_check_interval = interval;
+        self
+    }
+
+    /// Eject a backend after `limit` failed health checks or requests in a row. Defaults to 3.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `limit` is 0.
+    pub fn with_failure_limit(mut self, limit: u32) -> Self {
+        assert!(limit > 0, "a backend must fail at least once to be ejected");
+        self.failure_limit = limit;
+        self
+    }
+}
+
+struct Inner {
+    /// The client to the healthy backends, replaced when one is ejected or comes back.
+    client: RwLock<Arc<ShardedKvsClient>>,
+    backends: Mutex<HashMap<KvsAddr, Backend>>,
+    options: ClientOptions,
+    failure_limit: u32,
+}
+
+#[derive(Default)]
+struct Backend {
+    /// The health checks and requests failed in a row.
+    failures: u32,
+    ejected: bool,
+}
+
+impl KvsProxy {
+    /// Create a proxy to the servers on `backends`, connecting to them with `client`.
+    pub fn new<A: Into<KvsAddr>>(
+        backends: impl IntoIterator<Item = A>,
+        client: ClientOptions,
+        options: ProxyOptions,
+    ) -> Self {
+        let backends: Vec<KvsAddr> = backends.into_iter().map(Into::into).collect();
+        let sharded = ShardedKvsClient::new(backends.clone(), client.clone(), options.pool);
+        let mut health_check = client.clone();
+        health_check
+            .connect_timeout
+            .get_or_insert(DEFAULT_CONNECT_TIMEOUT);
+        let inner = Arc::new(Inner {
+            client: RwLock::new(Arc::new(sharded)),
+            backends: Mutex::new(
+                backends
+                    .into_iter()
+                    .map(|addr| (addr, Backend::default()))
+                    .collect(),
+            ),
+            options: health_check,
+            failure_limit: options.failure_limit,
+        });
+
+        let weak = Arc::downgrade(&inner);
+        let interval = options.health_check_interval;
+        thread::spawn(move || check_health(weak, interval));
+        KvsProxy { inner }
+    }
+
+    fn client(&self) -> Arc<ShardedKvsClient> {
+        self.inner.client.read().unwrap().clone()
+    }
+
+    /// Forward a request on `key` with `f`, counting the failures of its backend.
+    fn forward<T>(&self, key: &str, f: impl FnOnce(&ShardedKvsClient) -> Result<T>) -> Result<T> {
+        let client = self.client();
+        let backend = client.node_for(key).cloned();
+        let result = f(&client);
+        if let Some(backend) = backend {
+            match result {
+                Err(ref err) if is_broken(err) => self.inner.failed(&backend),
+                Err(_) => {}
+                Ok(_) => self.inner.succeeded(&backend),
+            }
+        }
+        result
+    }
+
+    /// Forward a request on many items with `f`, one for the items of each
+    /// backend in parallel, counting the failures of each backend. The results
+    /// are put back in the order of `items`.
+    fn forward_many<I: Send, T: Send>(
+        &self,
+        items: Vec<I>,
+        key: fn(&I) -> &str,
+        f: impl Fn(&ShardedKvsClient, Vec<I>) -> Result<Vec<T>> + Sync,
+    ) -> Result<Vec<T>> {
+        let client = self.client();
+        let len = items.len();
+        let mut backends: HashMap<Option<&KvsAddr>, (Vec<usize>, Vec<I>)> = HashMap::new();
+        for (index, item) in items.into_iter().enumerate() {
+            let (indexes, items) = backends.entry(client.node_for(key(&item))).or_default();
+            indexes.push(index);
+            items.push(item);
+        }
+        let f = &f;
+        let mut results: Vec<Option<T>> = (0..len).map(|_| None).collect();
+        thread::scope(|scope| {
+            let handles: Vec<_> = backends
+                .into_values()
+                .map(|(indexes, items)| {
+                    let first = key(&items[0]).to_owned();
+                    let handle = scope.spawn(move || self.forward(&first, |c| f(c, items)));
+                    (indexes, handle)
+                })
+                .collect();
+            for (indexes, handle) in handles {
+                for (index, result) in indexes.into_iter().zip(handle.join().unwrap()?) {
+                    results[index] = Some(result);
+                }
+            }
+            Ok(results.into_iter().map(Option::unwrap).collect())
+        })
+    }
+}
+
+impl Inner {
+    fn failed(&self, addr: &KvsAddr) {
+        let mut backends = self.backends.lock().unwrap();
+        let backend = backends.entry(addr.clone()).or_default();
+        backend.failures += 1;
+        if backend.failures >= self.failure_limit && !backend.ejected {
+            warn!(
+                "ejecting backend {} after {} failures",
+                addr, backend.failures
+            );
+            backend.ejected = true;
+            let mut client = self.client.write().unwrap();
+            let mut sharded = ShardedKvsClient::clone(&client);
+            sharded.remove_node(addr);
+            *client = Arc::new(sharded);
+        }
+    }
+
+    fn succeeded(&self, addr: &KvsAddr) {
+        let mut backends = self.backends.lock().unwrap();
+        let backend = backends.entry(addr.clone()).or_default();
+        backend.failures = 0;
+        if backend.ejected {
+            info!("backend {} is back", addr);
+            backend.ejected = false;
+            let mut client = self.client.write().unwrap();
+            let mut sharded = ShardedKvsClient::clone(&client);
+            sharded.add_node(addr.clone());
+            *client = Arc::new(sharded);
+        }
+    }
+}
+
+/// Connect to each backend every `interval`, for as long as the proxy lives.
+fn check_health(inner: Weak<Inner>, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        let inner = match inner.upgrade() {
+            Some(inner) => inner,
+            None => return,
+        };
+        let backends: Vec<KvsAddr> = inner.backends.lock().unwrap().keys().cloned().collect();
+        for addr in backends {
+            match KvsClient::connect(&addr, &inner.options) {
+                Ok(_) => inner.succeeded(&addr),
+                Err(err) => {
+                    debug!("health check of backend {} failed: {}", addr, err);
+                    inner.failed(&addr);
+                }
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvsProxy {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.forward(&key.clone(), |client| client.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.forward(&key.clone(), |client| client.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.forward(&key.clone(), |client| match client.remove(key)? {
+            Some(_) => Ok(()),
+            None => Err(KvsError::KeyNotFound),
+        })
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan(&self, _f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        Err(KvsError::InvalidRequest(
+            "Scanning is not supported by the proxy".to_owned(),
+        ))
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.forward_many(keys, String::as_str, |client, keys| client.mget(keys))
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.forward_many(
+            pairs,
+            |(key, _)| key.as_str(),
+            |client, pairs| {
+                let len = pairs.len();
+                client.mset(pairs)?;
+                Ok(vec![(); len])
+            },
+        )?;
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.forward_many(keys, String::as_str, |client, keys| {
+            let removed = client.mdel(keys)?;
+            Ok(removed.iter().map(Option::is_some).collect())
+        })
+    }
+
+    fn name(&self) -> &'static str {
+        "proxy"
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        Ok(0)
+    }
+}
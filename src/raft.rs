@@ -0,0 +1,956 @@
+//! A cluster of servers replicating their writes with Raft.
+//!
+//! Writes are appended to the log of the leader, sent to the other nodes, and
+//! applied to the engines once a majority of the nodes have them. Reads are
+//! served by the leader, once a majority of the nodes confirmed it still leads
+//! (see "read index" in the Raft paper), so that they never see stale data.
+//!
+//! Nodes talk over the kvs protocol: a node connects to its peers as a client,
+//! and turns the connection into a channel for Raft messages with `Request::Raft`.
+//!
+//! A node records the last entry it applied to its engine, and only applies the
+//! entries after it when restarted. The log itself is never compacted: it keeps
+//! every write, and grows without bound.
+use crate::client::{ClientOptions, KvsClient};
+use crate::engine::{KvsEngine, Result};
+use crate::error::KvsError;
+use crate::network::{Request, Response};
+use crate::transport::{KvsAddr, Stream};
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the leader tells the other nodes it is alive.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+/// How long a node waits for the leader before starting an election,
+/// picked at random between this and twice this.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(600);
+/// How long a node waits for a peer to answer a message.
+const RPC_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a node waits before retrying a peer it failed to reach.
+const RETRY_DELAY: Duration = Duration::from_millis(100);
+/// How long a request waits to be committed, or for the leadership to be confirmed.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many entries the leader sends at most in a message.
+const MAX_ENTRIES: usize = 1000;
+
+/// The nodes of a Raft cluster, see [`KvsServer::with_cluster`](crate::KvsServer::with_cluster).
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::{ClusterConfig, KvsAddr};
+/// let nodes: Vec<KvsAddr> = vec![
+///     "127.0.0.1:4000".parse().unwrap(),
+///     "127.0.0.1:4001".parse().unwrap(),
+///     "127.0.0.1:4002".parse().unwrap(),
+/// ];
+/// // The configuration of the second node, keeping its Raft state in `./raft`.
+/// let cluster = ClusterConfig::new(nodes, 1, "./raft");
+/// ```
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    nodes: Vec<KvsAddr>,
+    id: usize,
+    dir: PathBuf,
+    options: ClientOptions,
+}
+
+impl ClusterConfig {
+    /// Run as the node `id` of a cluster of `nodes`, keeping the Raft state in `dir`.
+    ///
+    /// `nodes` are the addresses the nodes serve clients on, in the same order on
+    /// every node: `nodes[id]` is the address of this node.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not an index of `nodes`.
+    pub fn new(nodes: Vec<KvsAddr>, id: usize, dir: impl Into<PathBuf>) -> Self {
+        assert!(id < nodes.len(), "the node is not part of the cluster");
+        ClusterConfig {
+            nodes,
+            id,
+            dir: dir.into(),
+            options: ClientOptions::default(),
+        }
+    }
+
+    /// Connect to the other nodes with `options`, e.g. for TLS or credentials.
+    ///
+    /// The nodes need an ACL, and must authenticate as a user with admin access to every key.
+    pub fn with_client_options(mut self, options: ClientOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// An entry of the Raft log: a write, or nothing for the entry a new leader
+/// starts its term with.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) struct Entry {
+    term: u64,
+    request: Option<Request>,
+}
+
+/// A message from a node to another.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum RaftRequest {
+    /// Ask for a vote in an election.
+    Vote {
+        term: u64,
+        candidate: usize,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// Append entries to the log, or just tell the leader is alive if there are none.
+    Append {
+        term: u64,
+        leader: usize,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry>,
+        leader_commit: u64,
+    },
+}
+
+/// The answer to a [`RaftRequest`].
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum RaftResponse {
+    Vote {
+        term: u64,
+        granted: bool,
+    },
+    /// `match_index` is the last entry the logs share on success, and a hint
+    /// where to go back to on failure.
+    Append {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+/// The log and the vote of a node, persisted in a directory.
+struct Log {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+    writer: BufWriter<File>,
+}
+
+#[derive(Default, Deserialize, Serialize)]
+struct Vote {
+    term: u64,
+    voted_for: Option<usize>,
+}
+
+impl Log {
+    fn open(dir: PathBuf) -> Result<(Log, Vote)> {
+        fs::create_dir_all(&dir)?;
+        let vote = match File::open(dir.join("vote.json")) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file))?,
+            Err(_) => Vote::default(),
+        };
+        let mut entries = Vec::new();
+        let mut torn = false;
+        if let Ok(file) = File::open(dir.join("log.json")) {
+            for line in BufReader::new(file).lines() {
+                // A crash may leave the last entry half written.
+                match serde_json::from_str(&line?) {
+                    Ok(entry) => entries.push(entry),
+                    Err(_) => {
+                        torn = true;
+                        break;
+                    }
+                }
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join("log.json"))?;
+        let mut log = Log {
+            writer: BufWriter::new(file),
+            dir,
+            entries,
+        };
+        if torn {
+            log.rewrite()?;
+        }
+        Ok((log, vote))
+    }
+
+    /// The index of the last entry applied to the engine, 0 if unknown.
+    fn applied(&self) -> u64 {
+        fs::read_to_string(self.dir.join("applied"))
+            .ok()
+            .and_then(|applied| applied.trim().parse().ok())
+            .unwrap_or(0)
+            .min(self.last_index())
+    }
+
+    /// Record that the entries up to `index` are applied to the engine.
+    ///
+    /// It is not synced: losing it only applies the entries again on restart,
+    /// which leaves the engine the same.
+    fn save_applied(&self, index: u64) -> Result<()> {
+        let tmp = self.dir.join("applied.tmp");
+        fs::write(&tmp, index.to_string())?;
+        fs::rename(&tmp, self.dir.join("applied"))?;
+        Ok(())
+    }
+
+    fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    /// The term of the entry at `index`, 0 before the first one.
+    fn term_at(&self, index: u64) -> u64 {
+        match index {
+            0 => 0,
+            index => self.entries[index as usize - 1].term,
+        }
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.entries[index as usize - 1]
+    }
+
+    fn append(&mut self, entries: Vec<Entry>) -> Result<()> {
+        for entry in entries {
+            serde_json::to_writer(&mut self.writer, &entry)?;
+            self.writer.write_all(b"\n")?;
+            self.entries.push(entry);
+        }
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Drop the entries from `index` on.
+    fn truncate(&mut self, index: u64) -> Result<()> {
+        self.entries.truncate(index as usize - 1);
+        self.rewrite()
+    }
+
+    fn rewrite(&mut self) -> Result<()> {
+        let tmp = self.dir.join("log.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp, self.dir.join("log.json"))?;
+        let file = OpenOptions::new()
+            .append(true)
+            .open(self.dir.join("log.json"))?;
+        self.writer = BufWriter::new(file);
+        Ok(())
+    }
+
+    fn save_vote(&self, vote: &Vote) -> Result<()> {
+        let tmp = self.dir.join("vote.tmp");
+        let mut file = File::create(&tmp)?;
+        serde_json::to_writer(&mut file, vote)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join("vote.json"))?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// Apply a committed write to the engine, see [`apply`].
+type Apply = Box<dyn FnMut(&Request) -> Result<Vec<bool>> + Send>;
+
+/// The outcome of a write, sent to the request waiting for it to commit.
+type Outcome = mpsc::Sender<Result<Vec<bool>>>;
+
+struct State {
+    id: usize,
+    nodes: Vec<KvsAddr>,
+    term: u64,
+    voted_for: Option<usize>,
+    log: Log,
+    commit: u64,
+    applied: u64,
+    role: Role,
+    leader: Option<usize>,
+    /// When to start an election if the leader is not heard from.
+    deadline: Instant,
+    /// The votes received by a candidate.
+    votes: Vec<bool>,
+    /// The term a candidate asked each node for its vote in.
+    asked: Vec<u64>,
+    /// The next entry the leader sends to each node.
+    next_index: Vec<u64>,
+    /// The last entry the leader knows each node has.
+    match_index: Vec<u64>,
+    /// When the leader sends the next heartbeat to each node.
+    heartbeat: Vec<Instant>,
+    /// Reads wait for a round of heartbeats started after them.
+    read_round: u64,
+    sent_round: Vec<u64>,
+    acked_round: Vec<u64>,
+    /// The writes of this leader waiting to be committed, by index, with their term.
+    pending: HashMap<u64, (u64, Outcome)>,
+    apply: Apply,
+    stopped: bool,
+}
+
+impl State {
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn reset_deadline(&mut self) {
+        let millis = ELECTION_TIMEOUT.as_millis() as u64;
+        let timeout = rand::thread_rng().gen_range(millis, 2 * millis);
+        self.deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    fn save_vote(&self) -> Result<()> {
+        self.log.save_vote(&Vote {
+            term: self.term,
+            voted_for: self.voted_for,
+        })
+    }
+
+    /// The error telling a client this node cannot serve its request.
+    fn not_leader(&self) -> KvsError {
+        match self.leader {
+            Some(leader) if leader != self.id => KvsError::Redirect(self.nodes[leader].to_string()),
+            _ => KvsError::ServerBusy("No leader elected".to_owned()),
+        }
+    }
+
+    fn become_follower(&mut self, term: u64) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.save_vote()?;
+        }
+        if self.role != Role::Follower {
+            info!("node {} is a follower in term {}", self.id, self.term);
+            self.role = Role::Follower;
+            self.reset_deadline();
+        }
+        // The writes still waiting may or may not be committed by the next leader.
+        self.pending.clear();
+        Ok(())
+    }
+
+    fn start_election(&mut self) -> Result<()> {
+        self.term += 1;
+        self.role = Role::Candidate;
+        self.voted_for = Some(self.id);
+        self.leader = None;
+        self.save_vote()?;
+        info!("node {} starts an election in term {}", self.id, self.term);
+        self.votes = vec![false; self.nodes.len()];
+        self.votes[self.id] = true;
+        self.reset_deadline();
+        self.count_votes()
+    }
+
+    fn count_votes(&mut self) -> Result<()> {
+        if self.votes.iter().filter(|vote| **vote).count() < self.majority() {
+            return Ok(());
+        }
+        info!("node {} is the leader in term {}", self.id, self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        let now = Instant::now();
+        self.heartbeat = vec![now; self.nodes.len()];
+        self.next_index = vec![self.log.last_index() + 1; self.nodes.len()];
+        self.match_index = vec![0; self.nodes.len()];
+        // Entries of previous terms are only committed along with one of this term.
+        self.append(None).map(|_| ())
+    }
+
+    /// Append a write to the log of the leader, returning its index.
+    fn append(&mut self, request: Option<Request>) -> Result<u64> {
+        let term = self.term;
+        self.log.append(vec![Entry { term, request }])?;
+        let index = self.log.last_index();
+        self.match_index[self.id] = index;
+        self.advance_commit()?;
+        Ok(index)
+    }
+
+    /// Commit the entries of this term a majority of the nodes have.
+    fn advance_commit(&mut self) -> Result<()> {
+        for index in (self.commit + 1..=self.log.last_index()).rev() {
+            if self.log.term_at(index) != self.term {
+                break;
+            }
+            let count = self.match_index.iter().filter(|m| **m >= index).count();
+            if count >= self.majority() {
+                self.commit = index;
+                break;
+            }
+        }
+        self.apply_committed()
+    }
+
+    fn apply_committed(&mut self) -> Result<()> {
+        if self.applied == self.commit {
+            return Ok(());
+        }
+        while self.applied < self.commit {
+            self.applied += 1;
+            let entry = self.log.entry(self.applied).clone();
+            let result = match entry.request {
+                Some(ref request) => (self.apply)(request),
+                None => Ok(Vec::new()),
+            };
+            if let Err(ref err) = result {
+                if !matches!(err, KvsError::KeyNotFound) {
+                    error!("failed to apply entry {}: {}", self.applied, err);
+                }
+            }
+            if let Some((term, outcome)) = self.pending.remove(&self.applied) {
+                if term == entry.term {
+                    let _ = outcome.send(result);
+                }
+            }
+        }
+        self.log.save_applied(self.applied)
+    }
+
+    fn handle(&mut self, request: RaftRequest) -> Result<RaftResponse> {
+        let node = match request {
+            RaftRequest::Vote { candidate, .. } => candidate,
+            RaftRequest::Append { leader, .. } => leader,
+        };
+        if node >= self.nodes.len() {
+            return Err(KvsError::InvalidRequest(format!(
+                "Unknown node {} in a cluster of {}",
+                node,
+                self.nodes.len()
+            )));
+        }
+        match request {
+            RaftRequest::Vote {
+                term,
+                candidate,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > self.term {
+                    self.become_follower(term)?;
+                }
+                let up_to_date = (last_log_term, last_log_index)
+                    >= (self.log.last_term(), self.log.last_index());
+                let granted = term == self.term
+                    && self.voted_for.is_none_or(|voted| voted == candidate)
+                    && up_to_date;
+                if granted {
+                    self.voted_for = Some(candidate);
+                    self.save_vote()?;
+                    self.reset_deadline();
+                }
+                Ok(RaftResponse::Vote {
+                    term: self.term,
+                    granted,
+                })
+            }
+            RaftRequest::Append {
+                term,
+                leader,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    return Ok(RaftResponse::Append {
+                        term: self.term,
+                        success: false,
+                        match_index: 0,
+                    });
+                }
+                self.become_follower(term)?;
+                self.leader = Some(leader);
+                self.reset_deadline();
+
+                if prev_log_index > self.log.last_index()
+                    || self.log.term_at(prev_log_index) != prev_log_term
+                {
+                    return Ok(RaftResponse::Append {
+                        term: self.term,
+                        success: false,
+                        match_index: prev_log_index.saturating_sub(1).min(self.log.last_index()),
+                    });
+                }
+                // Skip the entries the log already has, and drop the ones that conflict.
+                let mut index = prev_log_index;
+                let mut new = Vec::new();
+                for entry in entries {
+                    index += 1;
+                    if new.is_empty() && index <= self.log.last_index() {
+                        if self.log.term_at(index) == entry.term {
+                            continue;
+                        }
+                        self.log.truncate(index)?;
+                    }
+                    new.push(entry);
+                }
+                self.log.append(new)?;
+                let match_index = index;
+
+                let commit = leader_commit.min(match_index);
+                if commit > self.commit {
+                    self.commit = commit;
+                    self.apply_committed()?;
+                }
+                Ok(RaftResponse::Append {
+                    term: self.term,
+                    success: true,
+                    match_index,
+                })
+            }
+        }
+    }
+
+    /// The next message to send to `peer`, if any, with the read round it confirms.
+    fn next_request(&mut self, peer: usize) -> Option<(RaftRequest, u64)> {
+        let now = Instant::now();
+        match self.role {
+            Role::Candidate if self.asked[peer] != self.term => {
+                self.asked[peer] = self.term;
+                let request = RaftRequest::Vote {
+                    term: self.term,
+                    candidate: self.id,
+                    last_log_index: self.log.last_index(),
+                    last_log_term: self.log.last_term(),
+                };
+                Some((request, 0))
+            }
+            Role::Leader
+                if self.next_index[peer] <= self.log.last_index()
+                    || self.heartbeat[peer] <= now
+                    || self.sent_round[peer] < self.read_round =>
+            {
+                self.heartbeat[peer] = now + HEARTBEAT_INTERVAL;
+                self.sent_round[peer] = self.read_round;
+                let prev_log_index = self.next_index[peer] - 1;
+                let end = self
+                    .log
+                    .last_index()
+                    .min(prev_log_index + MAX_ENTRIES as u64);
+                let entries = self.log.entries[prev_log_index as usize..end as usize].to_vec();
+                let request = RaftRequest::Append {
+                    term: self.term,
+                    leader: self.id,
+                    prev_log_index,
+                    prev_log_term: self.log.term_at(prev_log_index),
+                    entries,
+                    leader_commit: self.commit,
+                };
+                Some((request, self.read_round))
+            }
+            _ => None,
+        }
+    }
+
+    /// Handle the answer of `peer` to a message sent in `term`.
+    fn handle_reply(
+        &mut self,
+        peer: usize,
+        term: u64,
+        round: u64,
+        reply: RaftResponse,
+    ) -> Result<()> {
+        let (reply_term, granted, success, match_index) = match reply {
+            RaftResponse::Vote { term, granted } => (term, granted, false, 0),
+            RaftResponse::Append {
+                term,
+                success,
+                match_index,
+            } => (term, false, success, match_index),
+        };
+        if reply_term > self.term {
+            return self.become_follower(reply_term);
+        }
+        if term != self.term {
+            return Ok(());
+        }
+        match self.role {
+            Role::Candidate if granted => {
+                self.votes[peer] = true;
+                self.count_votes()
+            }
+            Role::Leader => {
+                // Answering the leader of this term confirms it still leads.
+                self.acked_round[peer] = self.acked_round[peer].max(round);
+                if success {
+                    self.match_index[peer] = self.match_index[peer].max(match_index);
+                    self.next_index[peer] = self.match_index[peer] + 1;
+                    self.advance_commit()
+                } else {
+                    self.next_index[peer] = (match_index + 1).min(self.next_index[peer] - 1).max(1);
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a read started in `round` of `term` may be served.
+    fn confirmed(&self, term: u64, round: u64) -> bool {
+        let acked = (0..self.nodes.len())
+            .filter(|node| *node == self.id || self.acked_round[*node] >= round)
+            .count();
+        acked >= self.majority() && self.log.term_at(self.commit) == term
+    }
+}
+
+/// A node of a Raft cluster.
+#[derive(Clone)]
+pub(crate) struct Raft {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+    options: ClientOptions,
+}
+
+impl Raft {
+    /// Start the node, applying the committed writes with `apply`.
+    fn start(config: ClusterConfig, apply: Apply) -> Result<Raft> {
+        let (log, vote) = Log::open(config.dir)?;
+        // The entries applied before were committed, and are in the engine already.
+        let applied = log.applied();
+        let (id, n) = (config.id, config.nodes.len());
+        let mut state = State {
+            id: config.id,
+            nodes: config.nodes,
+            term: vote.term,
+            voted_for: vote.voted_for,
+            log,
+            commit: applied,
+            applied,
+            role: Role::Follower,
+            leader: None,
+            deadline: Instant::now(),
+            votes: vec![false; n],
+            asked: vec![0; n],
+            next_index: vec![1; n],
+            match_index: vec![0; n],
+            heartbeat: vec![Instant::now(); n],
+            read_round: 0,
+            sent_round: vec![0; n],
+            acked_round: vec![0; n],
+            pending: HashMap::new(),
+            apply,
+            stopped: false,
+        };
+        state.reset_deadline();
+
+        let mut options = config.options;
+        options.connect_timeout.get_or_insert(RPC_TIMEOUT);
+        let raft = Raft {
+            shared: Arc::new(Shared {
+                state: Mutex::new(state),
+                changed: Condvar::new(),
+                options,
+            }),
+        };
+        let ticker = raft.clone();
+        thread::spawn(move || ticker.tick());
+        for peer in (0..n).filter(|peer| *peer != id) {
+            let raft = raft.clone();
+            thread::spawn(move || raft.talk_to(peer));
+        }
+        Ok(raft)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.shared.state.lock().unwrap()
+    }
+
+    /// Stop the threads of the node.
+    pub(crate) fn stop(&self) {
+        self.lock().stopped = true;
+        self.shared.changed.notify_all();
+    }
+
+    /// Commit `request`, returning the outcome of applying it.
+    fn propose(&self, request: Request) -> Result<Vec<bool>> {
+        let (sender, receiver) = mpsc::channel();
+        {
+            let mut state = self.lock();
+            if state.role != Role::Leader {
+                return Err(state.not_leader());
+            }
+            let (term, index) = (state.term, state.log.last_index() + 1);
+            state.pending.insert(index, (term, sender));
+            state.append(Some(request))?;
+        }
+        self.shared.changed.notify_all();
+        match receiver.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::ServerError(
+                "Timed out waiting for the write to commit".to_owned(),
+            )),
+            Err(RecvTimeoutError::Disconnected) => Err(KvsError::ServerError(
+                "Lost the leadership, the write may or may not be applied".to_owned(),
+            )),
+        }
+    }
+
+    /// Run `read` once this node is confirmed to lead the cluster, and has
+    /// applied every write committed before.
+    fn read<T>(&self, read: impl FnOnce() -> Result<T>) -> Result<T> {
+        let deadline = Instant::now() + REQUEST_TIMEOUT;
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return Err(state.not_leader());
+        }
+        state.read_round += 1;
+        let (term, round) = (state.term, state.read_round);
+        self.shared.changed.notify_all();
+        while !state.confirmed(term, round) {
+            let now = Instant::now();
+            if state.role != Role::Leader || state.term != term {
+                return Err(state.not_leader());
+            }
+            if now >= deadline {
+                return Err(KvsError::ServerBusy(
+                    "Timed out confirming the leadership".to_owned(),
+                ));
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+        drop(state);
+        read()
+    }
+
+    /// Start an election whenever the leader is not heard from in time.
+    fn tick(&self) {
+        let mut state = self.lock();
+        while !state.stopped {
+            let now = Instant::now();
+            if state.role != Role::Leader && state.deadline <= now {
+                if let Err(err) = state.start_election() {
+                    error!("node {} failed to start an election: {}", state.id, err);
+                }
+                self.shared.changed.notify_all();
+                continue;
+            }
+            let timeout = if state.role == Role::Leader {
+                HEARTBEAT_INTERVAL
+            } else {
+                state.deadline - now
+            };
+            state = self.shared.changed.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Send the messages for `peer`, one at a time.
+    fn talk_to(&self, peer: usize) {
+        let addr = self.lock().nodes[peer].clone();
+        let mut connection = None;
+        loop {
+            let (request, term, round) = {
+                let mut state = self.lock();
+                loop {
+                    if state.stopped {
+                        return;
+                    }
+                    if let Some((request, round)) = state.next_request(peer) {
+                        break (request, state.term, round);
+                    }
+                    state = self
+                        .shared
+                        .changed
+                        .wait_timeout(state, HEARTBEAT_INTERVAL / 2)
+                        .unwrap()
+                        .0;
+                }
+            };
+            let reply = match connection {
+                Some(ref mut connection) => call(connection, &request),
+                None => connect(&addr, &self.shared.options).and_then(|mut new| {
+                    let reply = call(&mut new, &request);
+                    connection = Some(new);
+                    reply
+                }),
+            };
+            match reply {
+                Ok(reply) => {
+                    let mut state = self.lock();
+                    if let Err(err) = state.handle_reply(peer, term, round, reply) {
+                        error!("node {} failed: {}", state.id, err);
+                    }
+                    drop(state);
+                    self.shared.changed.notify_all();
+                }
+                Err(err) => {
+                    debug!("failed to reach node {} at {}: {}", peer, addr, err);
+                    connection = None;
+                    if let RaftRequest::Vote { .. } = request {
+                        self.lock().asked[peer] = 0;
+                    }
+                    thread::sleep(RETRY_DELAY);
+                }
+            }
+        }
+    }
+
+    /// Answer the messages of a peer on `stream`, until it leaves.
+    pub(crate) fn serve_peer(&self, stream: Stream) -> Result<()> {
+        // Peers only talk while leading or running for election, and may stay idle for long.
+        stream.set_read_timeout(None)?;
+        let mut reader = BufReader::new(stream);
+        send(reader.get_mut(), &Response::Ok(None))?;
+        loop {
+            let request = match read(&mut reader) {
+                Ok(request) => request,
+                Err(KvsError::SerdeJsonError(ref err)) if err.is_eof() => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            let reply = self.lock().handle(request)?;
+            self.shared.changed.notify_all();
+            send(reader.get_mut(), &reply)?;
+        }
+    }
+}
+
+fn connect(addr: &KvsAddr, options: &ClientOptions) -> Result<BufReader<Stream>> {
+    let stream = KvsClient::connect(addr, options)?.upgrade(Request::Raft)?;
+    stream.set_read_timeout(Some(RPC_TIMEOUT))?;
+    stream.set_write_timeout(Some(RPC_TIMEOUT))?;
+    Ok(BufReader::new(stream))
+}
+
+fn call(connection: &mut BufReader<Stream>, request: &RaftRequest) -> Result<RaftResponse> {
+    send(connection.get_mut(), request)?;
+    read(connection)
+}
+
+fn send(stream: &mut Stream, message: &impl Serialize) -> Result<()> {
+    let mut writer = BufWriter::new(stream);
+    serde_json::to_writer(&mut writer, message)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read<T: DeserializeOwned>(reader: &mut BufReader<Stream>) -> Result<T> {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    Ok(T::deserialize(&mut de)?)
+}
+
+/// Apply a committed write to `engine`, returning whether each key existed for removals.
+fn apply(engine: &impl KvsEngine, request: &Request) -> Result<Vec<bool>> {
+    match request.clone() {
+        Request::Set { key, value } => engine.set(key, value).map(|_| Vec::new()),
+        Request::Remove { key } => engine.remove(key).map(|_| vec![true]),
+        Request::MSet { pairs } => engine.set_many(pairs).map(|_| Vec::new()),
+        Request::MDel { keys } => engine.remove_many(keys),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// An engine whose writes go through the Raft log of a cluster.
+///
+/// Writes and reads are only served by the leader: the other nodes answer
+/// with a redirect to it.
+#[derive(Clone)]
+pub(crate) struct Cluster<E: KvsEngine> {
+    engine: E,
+    raft: Raft,
+}
+
+impl<E: KvsEngine> Cluster<E> {
+    pub(crate) fn start(engine: E, config: ClusterConfig) -> Result<Self> {
+        let applied = engine.clone();
+        let raft = Raft::start(
+            config,
+            Box::new(move |request: &Request| apply(&applied, request)),
+        )?;
+        Ok(Cluster { engine, raft })
+    }
+
+    pub(crate) fn raft(&self) -> Raft {
+        self.raft.clone()
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Cluster<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.raft.propose(Request::Set { key, value })?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.raft.read(|| self.engine.get(key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.raft.propose(Request::Remove { key })?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.engine.scan(f)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.raft.read(|| self.engine.get_many(keys))
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.raft.propose(Request::MSet { pairs })?;
+        Ok(())
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.raft.propose(Request::MDel { keys })
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        self.engine.key_count()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.engine.disk_size()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
@@ -0,0 +1,340 @@
+//! Primary/replica replication.
+//!
+//! Servers wrap their engine in [`Replicated`], which publishes every write to the
+//! replicas following it, in the order they are applied. A replica sends
+//! `Request::Replicate` to its primary, which answers with a copy of its data,
+//! then streams the writes made since the copy started.
+use crate::client::{ClientOptions, KvsClient};
+use crate::engine::{KvsEngine, Result};
+use crate::error::KvsError;
+use crate::network::Response;
+use crate::shutdown::ShutdownHandle;
+use crate::transport::{KvsAddr, Stream};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+
+/// How many writes a replica may lag behind before the primary drops it.
+const FEED_CAPACITY: usize = 10_000;
+/// How often the primary tells an idle replica it is still there.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a replica waits for the primary before connecting again.
+pub(crate) const PRIMARY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a replica waits before connecting again after a failure.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A message of the replication stream.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub(crate) enum Feed {
+    /// Set the value of a key
+    Set { key: String, value: String },
+    /// Remove a key, which may not exist
+    Remove { key: String },
+    /// The copy of the data is complete, the writes that follow happened since it started
+    Synced,
+    /// Nothing happened, the primary is still alive
+    Heartbeat,
+}
+
+/// An engine publishing its writes to the replicas following it.
+///
+/// When it is itself a replica, the writes of clients are rejected with a redirect
+/// to its primary, and it only takes the writes of the primary.
+#[derive(Clone)]
+pub(crate) struct Replicated<E: KvsEngine> {
+    engine: E,
+    primary: Option<Arc<KvsAddr>>,
+    replicas: Arc<Replicas>,
+    /// Whether the writes are published here, rather than by the engine wrapped.
+    publishes: bool,
+}
+
+/// The replicas following an engine.
+///
+/// Without replicas, the writes run concurrently. Once one is attached, they
+/// run one at a time, so that the replicas see them in the order they are applied.
+#[derive(Default)]
+struct Replicas {
+    senders: Mutex<Vec<SyncSender<Feed>>>,
+    /// Whether `senders` may not be empty.
+    attached: AtomicBool,
+    /// Taken shared by the writes running without replicas, and exclusively to
+    /// attach one, which waits for them to complete.
+    gate: RwLock<()>,
+    /// Taken by the writes while replicas are attached, over the write and its feed.
+    order: Mutex<()>,
+}
+
+impl<E: KvsEngine> Replicated<E> {
+    pub(crate) fn new(engine: E, primary: Option<KvsAddr>) -> Self {
+        Replicated {
+            engine,
+            primary: primary.map(Arc::new),
+            replicas: Arc::new(Replicas::default()),
+            publishes: true,
+        }
+    }
+
+    /// An engine writing through `engine`, whose writes are published by `applied`
+    /// as it applies them, e.g. the engine the Raft log of a cluster node applies to.
+    ///
+    /// The replicas subscribing to it follow `applied`.
+    pub(crate) fn published_by<F: KvsEngine>(engine: E, applied: &Replicated<F>) -> Self {
+        Replicated {
+            engine,
+            primary: None,
+            replicas: applied.replicas.clone(),
+            publishes: false,
+        }
+    }
+
+    /// Receive the writes made from now on.
+    ///
+    /// A replica falling more than `FEED_CAPACITY` writes behind is dropped, and sees
+    /// the channel disconnect.
+    pub(crate) fn subscribe(&self) -> Result<Receiver<Feed>> {
+        let (sender, receiver) = mpsc::sync_channel(FEED_CAPACITY);
+        let replicas = &self.replicas;
+        let _gate = replicas.gate.write().map_err(|err| err.to_string())?;
+        let _order = replicas.order.lock().map_err(|err| err.to_string())?;
+        let mut senders = replicas.senders.lock().map_err(|err| err.to_string())?;
+        senders.push(sender);
+        replicas.attached.store(true, Ordering::SeqCst);
+        Ok(receiver)
+    }
+
+    /// Apply a write of the primary.
+    fn apply(&self, feed: Feed) -> Result<()> {
+        match feed {
+            Feed::Set { key, value } => {
+                let feeds = vec![Feed::Set {
+                    key: key.clone(),
+                    value: value.clone(),
+                }];
+                self.publish(feeds, |engine| engine.set(key, value))
+            }
+            Feed::Remove { key } => {
+                let feeds = vec![Feed::Remove { key: key.clone() }];
+                self.publish(feeds, |engine| match engine.remove(key) {
+                    Err(KvsError::KeyNotFound) => Ok(()),
+                    result => result,
+                })
+            }
+            Feed::Synced | Feed::Heartbeat => Ok(()),
+        }
+    }
+
+    /// Reject the writes of clients if this is a replica.
+    fn redirect(&self) -> Result<()> {
+        match self.primary {
+            Some(ref primary) => Err(KvsError::Redirect(primary.to_string())),
+            None => Ok(()),
+        }
+    }
+
+    /// Run `write` on the engine, and send `feeds` to the replicas if it succeeds.
+    fn publish<T>(&self, feeds: Vec<Feed>, write: impl FnOnce(&E) -> Result<T>) -> Result<T> {
+        if !self.publishes {
+            return write(&self.engine);
+        }
+        let replicas = &self.replicas;
+        if !replicas.attached.load(Ordering::SeqCst) {
+            let _gate = replicas.gate.read().map_err(|err| err.to_string())?;
+            if !replicas.attached.load(Ordering::SeqCst) {
+                return write(&self.engine);
+            }
+        }
+
+        let _order = replicas.order.lock().map_err(|err| err.to_string())?;
+        let result = write(&self.engine)?;
+        let mut senders = replicas.senders.lock().map_err(|err| err.to_string())?;
+        senders.retain(|replica| {
+            feeds
+                .iter()
+                .all(|feed| match replica.try_send(feed.clone()) {
+                    Ok(()) => true,
+                    Err(mpsc::TrySendError::Full(_)) => {
+                        warn!(
+                            "dropping a replica more than {} writes behind",
+                            FEED_CAPACITY
+                        );
+                        false
+                    }
+                    Err(mpsc::TrySendError::Disconnected(_)) => false,
+                })
+        });
+        if senders.is_empty() {
+            replicas.attached.store(false, Ordering::SeqCst);
+        }
+        Ok(result)
+    }
+}
+
+impl<E: KvsEngine> KvsEngine for Replicated<E> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.redirect()?;
+        let feeds = vec![Feed::Set {
+            key: key.clone(),
+            value: value.clone(),
+        }];
+        self.publish(feeds, |engine| engine.set(key, value))
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.redirect()?;
+        let feeds = vec![Feed::Remove { key: key.clone() }];
+        self.publish(feeds, |engine| engine.remove(key))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn scan(&self, f: &mut dyn FnMut(String, String) -> Result<()>) -> Result<()> {
+        self.engine.scan(f)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.engine.get_many(keys)
+    }
+
+    fn set_many(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.redirect()?;
+        let feeds = pairs
+            .iter()
+            .map(|(key, value)| Feed::Set {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        self.publish(feeds, |engine| engine.set_many(pairs))
+    }
+
+    fn remove_many(&self, keys: Vec<String>) -> Result<Vec<bool>> {
+        self.redirect()?;
+        let feeds = keys
+            .iter()
+            .map(|key| Feed::Remove { key: key.clone() })
+            .collect();
+        self.publish(feeds, |engine| engine.remove_many(keys))
+    }
+
+    fn name(&self) -> &'static str {
+        self.engine.name()
+    }
+
+    fn key_count(&self) -> Result<usize> {
+        self.engine.key_count()
+    }
+
+    fn disk_size(&self) -> Result<u64> {
+        self.engine.disk_size()
+    }
+
+    fn compact(&self) -> Result<()> {
+        self.engine.compact()
+    }
+}
+
+/// Send a copy of the data of `store` to the replica on `stream`, then the writes
+/// made since, until the replica leaves or a shutdown is asked for.
+pub(crate) fn serve_replica<E: KvsEngine>(
+    store: &Replicated<E>,
+    stream: &mut Stream,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let feed = store.subscribe()?;
+    let mut writer = BufWriter::new(stream);
+    send(&mut writer, &Response::Ok(None))?;
+    store
+        .engine
+        .scan(&mut |key, value| send(&mut writer, &Feed::Set { key, value }))?;
+    send(&mut writer, &Feed::Synced)?;
+    writer.flush()?;
+
+    while !shutdown.is_shutdown() {
+        match feed.recv_timeout(HEARTBEAT_INTERVAL) {
+            Ok(message) => send(&mut writer, &message)?,
+            Err(RecvTimeoutError::Timeout) => send(&mut writer, &Feed::Heartbeat)?,
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(KvsError::StringError("the replica fell behind".to_owned()))
+            }
+        }
+        // Send the writes in a row together.
+        while let Ok(message) = feed.try_recv() {
+            send(&mut writer, &message)?;
+        }
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+fn send(writer: &mut impl Write, message: &impl Serialize) -> Result<()> {
+    serde_json::to_writer(writer, message)?;
+    Ok(())
+}
+
+/// Follow the writes of `primary` into `store`, until a shutdown is asked for.
+///
+/// The data of the primary replaces the local one first. When the connection
+/// breaks, the replica connects again and starts over.
+pub(crate) fn follow<E: KvsEngine>(
+    store: Replicated<E>,
+    primary: KvsAddr,
+    options: ClientOptions,
+    shutdown: ShutdownHandle,
+) {
+    while !shutdown.is_shutdown() {
+        if let Err(err) = sync(&store, &primary, &options, &shutdown) {
+            warn!("replication from {} failed: {}", primary, err);
+            thread::sleep(RECONNECT_DELAY);
+        }
+    }
+}
+
+fn sync<E: KvsEngine>(
+    store: &Replicated<E>,
+    primary: &KvsAddr,
+    options: &ClientOptions,
+    shutdown: &ShutdownHandle,
+) -> Result<()> {
+    let feed = KvsClient::connect(primary, options)?.replicate()?;
+    info!("replicating {}", primary);
+
+    // The keys the primary does not have once the copy is complete are stale.
+    let mut stale = HashSet::new();
+    store.engine.scan(&mut |key, _| {
+        stale.insert(key);
+        Ok(())
+    })?;
+    for message in feed {
+        if shutdown.is_shutdown() {
+            return Ok(());
+        }
+        match message? {
+            Feed::Synced => {
+                for key in stale.drain() {
+                    store.apply(Feed::Remove { key })?;
+                }
+                info!("in sync with {}", primary);
+            }
+            Feed::Set { key, value } => {
+                stale.remove(&key);
+                store.apply(Feed::Set { key, value })?;
+            }
+            message => store.apply(message)?,
+        }
+    }
+    Err(KvsError::StringError(
+        "the primary closed the connection".to_owned(),
+    ))
+}
@@ -0,0 +1,118 @@
+use crate::error::KvsError;
+use crate::network::Request;
+use rand::Rng;
+use std::cmp;
+use std::time::Duration;
+
+const DEFAULT_MAX_ATTEMPTS: u32 = 3;
+const DEFAULT_BASE_DELAY: Duration = Duration::from_millis(50);
+const DEFAULT_MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// When and how often a [`KvsClient`](crate::KvsClient) retries a failed request.
+///
+/// Requests that fail because the connection broke, or because the server is busy,
+/// are retried on a new connection after an exponential backoff with full jitter:
+/// the delay before the `n`th retry is picked at random between zero and
+/// `base * 2^(n - 1)`, capped to `max`.
+///
+/// Only idempotent requests are retried by default: get, and set since setting the
+/// same value twice changes nothing. A remove may have been applied before the
+/// connection broke, so retrying it can report a missing key, and retrying a flushall
+/// would remove the keys set meanwhile; they are only retried with
+/// [`with_non_idempotent_retries`](RetryPolicy::with_non_idempotent_retries).
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::{ClientOptions, KvsClient, Result, RetryPolicy};
+/// # use std::net::SocketAddr;
+/// # use std::time::Duration;
+/// #
+/// # fn main() -> Result<()> {
+/// let retry = RetryPolicy::default()
+///     .with_max_attempts(5)
+///     .with_backoff(Duration::from_millis(100), Duration::from_secs(5));
+/// let options = ClientOptions::default().with_retry(retry);
+/// let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+/// let mut client = KvsClient::connect(addr, &options)?;
+/// client.get("Key".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    non_idempotent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            base_delay: DEFAULT_BASE_DELAY,
+            max_delay: DEFAULT_MAX_DELAY,
+            non_idempotent: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Try a request at most `attempts` times, including the first one. Defaults to 3.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `attempts` is 0.
+    pub fn with_max_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "a request needs at least one attempt");
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Back off from `base` before the first retry, doubling up to `max`.
+    /// Defaults to 50 milliseconds and 2 seconds.
+    pub fn with_backoff(mut self, base: Duration, max: Duration) -> Self {
+        self.base_delay = base;
+        self.max_delay = max;
+        self
+    }
+
+    /// Retry the requests that are not idempotent as well, e.g. remove or flushall.
+    pub fn with_non_idempotent_retries(mut self) -> Self {
+        self.non_idempotent = true;
+        self
+    }
+
+    /// Whether `request` should be tried again after its `attempt`th try failed with `err`.
+    ///
+    /// `request` is `None` while connecting, which is always safe to retry.
+    pub(crate) fn should_retry(
+        &self,
+        attempt: u32,
+        request: Option<&Request>,
+        err: &KvsError,
+    ) -> bool {
+        if attempt >= self.max_attempts {
+            return false;
+        }
+        match err {
+            // The server refuses requests it is too busy to run, they are safe to retry.
+            KvsError::ServerBusy(_) => true,
+            KvsError::IoError(_) => {
+                self.non_idempotent || request.is_none_or(Request::is_idempotent)
+            }
+            _ => false,
+        }
+    }
+
+    /// How long to wait before trying again after the `attempt`th try failed.
+    pub(crate) fn delay(&self, attempt: u32) -> Duration {
+        let exp = self
+            .base_delay
+            .checked_mul(1u32 << cmp::min(attempt - 1, 31))
+            .unwrap_or(self.max_delay);
+        let cap = cmp::min(exp, self.max_delay);
+        let nanos = rand::thread_rng().gen_range(0, cap.as_nanos() as u64 + 1);
+        Duration::from_nanos(nanos)
+    }
+}
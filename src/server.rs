@@ -1,12 +1,34 @@
+use crate::auth::{Access, Acl};
+use crate::client::ClientOptions;
 use crate::engine::Result;
-use crate::network::{Request, Response};
+use crate::event_loop::EventLoop;
+use crate::http;
+use crate::limits::{is_timeout, Limits, MAX_MESSAGE_SIZE};
+use crate::metrics::METRICS;
+use crate::migration::{KeyRange, Migrating};
+use crate::network::{Request, Response, COMMANDS};
+use crate::raft::{Cluster, ClusterConfig, Raft};
+use crate::replication::{self, Replicated};
+use crate::shutdown::{Connections, ShutdownHandle};
+use crate::slowlog::{RequestSummary, SlowLog, SlowQuery};
+use crate::stats::Stats;
 use crate::thread_pool::ThreadPool;
-use crate::KvsEngine;
+use crate::tls::TlsServerConfig;
+use crate::transport::{KvsAddr, Listener, Stream};
+use crate::{KvsEngine, KvsError};
 use log::info;
+use rustls::ServerConfig;
 use serde::Deserialize;
-use std::io::Write;
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::mpsc;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long the server tries to tell a refused client it is busy.
+const REFUSE_TIMEOUT: Duration = Duration::from_secs(1);
 
 /// A server to listen to the kvs client.
 /// # Examples
@@ -33,11 +55,26 @@ use std::sync::mpsc;
 /// # }
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     store: E,
-    pool: T,
+    pool: Arc<T>,
     receiver: Option<mpsc::Receiver<()>>,
+    http_addr: Option<SocketAddr>,
+    metrics_addr: Option<SocketAddr>,
+    tls: Option<TlsServerConfig>,
+    acl: Option<Arc<Acl>>,
+    shutdown: ShutdownHandle,
+    shutdown_timeout: Duration,
+    connections: Arc<Connections>,
+    http_connections: Arc<Connections>,
+    event_loop: bool,
+    limits: Limits,
+    replica_of: Option<(KvsAddr, ClientOptions)>,
+    cluster: Option<ClusterConfig>,
+    migration_options: ClientOptions,
+    migration_file: Option<PathBuf>,
+    stats: Arc<Stats>,
 }
 
-impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
+impl<E: KvsEngine, T: ThreadPool + Send + Sync + 'static> KvsServer<E, T> {
     /// Create a server.
     ///  
     /// # Arguments
@@ -48,63 +85,746 @@ impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
     pub fn new(engine: E, pool: T, receiver: Option<mpsc::Receiver<()>>) -> Self {
         KvsServer {
             store: engine,
-            pool,
+            pool: Arc::new(pool),
             receiver,
+            http_addr: None,
+            metrics_addr: None,
+            tls: None,
+            acl: None,
+            shutdown: ShutdownHandle::default(),
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            connections: Arc::new(Connections::default()),
+            http_connections: Arc::new(Connections::default()),
+            event_loop: false,
+            limits: Limits::default(),
+            replica_of: None,
+            cluster: None,
+            migration_options: ClientOptions::default(),
+            migration_file: None,
+            stats: Arc::new(Stats::default()),
         }
     }
 
+    /// Keep the last `capacity` requests taking longer than `threshold` to run,
+    /// for clients to read with [`KvsClient::slowlog`](crate::KvsClient::slowlog).
+    /// Defaults to the last 128 requests over 10 milliseconds.
+    pub fn with_slowlog(mut self, threshold: Duration, capacity: usize) -> Self {
+        self.stats = Arc::new(Stats::new(SlowLog::new(threshold, capacity)));
+        self
+    }
+
+    /// Also serve the engine over HTTP on `addr` when the server runs.
+    ///
+    /// The gateway supports `GET`, `PUT` and `DELETE` on `/keys/{key}`, the `PUT`
+    /// body being the value. Missing keys are reported with a 404 status, and
+    /// errors come back as `{"error": ..}` JSON bodies.
+    pub fn with_http_addr(mut self, addr: SocketAddr) -> Self {
+        self.http_addr = Some(addr);
+        self
+    }
+
+    /// Serve the metrics of the process on `GET /metrics` at `addr` when the server
+    /// runs, in the Prometheus text format.
+    ///
+    /// They count the requests, errors and latency by command, the open connections,
+    /// the jobs queued on the thread pools, and the garbage ratio and compactions of
+    /// the `KvStore` log.
+    pub fn with_metrics_addr(mut self, addr: SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Encrypt the connections of kvs clients with TLS.
+    ///
+    /// The certificate and key files are loaded when the server runs.
+    pub fn with_tls(mut self, tls: TlsServerConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// Require clients to authenticate, and restrict what they can do with `acl`.
+    ///
+    /// A connection must start with an authentication request; any other request is
+    /// answered with an `Unauthorized` error until it succeeds. The HTTP gateway
+    /// expects the same credentials with basic authentication.
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(Arc::new(acl));
+        self
+    }
+
+    /// Wait at most `timeout` for the connections in flight when shutting down. Defaults to 30 seconds.
+    pub fn with_shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Close the connections that take longer than `timeout` to send the next bytes of a request.
+    pub fn with_read_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.read_timeout = Some(timeout);
+        self
+    }
+
+    /// Close the connections that do not accept the bytes of a response within `timeout`.
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Close the connections that send no request for `timeout`.
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// Refuse new kvs clients while `max` of them are connected.
+    ///
+    /// Refused clients get a `Busy` error, except over TLS where the connection is just closed.
+    pub fn with_max_connections(mut self, max: usize) -> Self {
+        self.limits.max_connections = Some(max);
+        self
+    }
+
+    /// Multiplex the connections on a readiness-based event loop (epoll, kqueue..)
+    /// instead of running each of them on a pool thread.
+    ///
+    /// The pool then only runs complete requests, so that slow or idle clients do
+    /// not hold its threads. TLS, clusters and replication are not supported in
+    /// this mode: such a server cannot follow a primary, nor be followed by replicas.
+    pub fn with_event_loop(mut self) -> Self {
+        self.event_loop = true;
+        self
+    }
+
+    /// Run as a replica of the server on `primary`, connecting to it with `options`.
+    ///
+    /// The data of the primary replaces the local one, then its writes are applied
+    /// as they happen. The replica serves reads, but rejects writes with a
+    /// [`Redirect`](KvsError::Redirect) to the primary.
+    ///
+    /// Replicas can be followed by other replicas. The primary needs an ACL, and
+    /// the replica must authenticate as a user with admin access to every key.
+    pub fn with_replica_of(mut self, primary: impl Into<KvsAddr>, options: ClientOptions) -> Self {
+        self.replica_of = Some((primary.into(), options));
+        self
+    }
+
+    /// Run as a node of a Raft cluster.
+    ///
+    /// Writes are committed once a majority of the nodes have them, and reads are
+    /// served by the leader once a majority confirmed it still leads. The other
+    /// nodes answer with a [`Redirect`](KvsError::Redirect) to the leader, or a
+    /// busy error while no leader is elected. When the leader dies, the others
+    /// elect a new one.
+    ///
+    /// A cluster node cannot be a replica, but can be followed by replicas. The
+    /// nodes need an ACL, see [`ClusterConfig::with_client_options`].
+    pub fn with_cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.cluster = Some(cluster);
+        self
+    }
+
+    /// Connect to the targets of migrations with `options`.
+    ///
+    /// The admin of a server with an ACL can move a range of keys to another server
+    /// with a migration: the server copies them to the target while still serving
+    /// them, forwarding the writes to the range meanwhile. Once done, the range is
+    /// redirected to the target. Over an ACL of the target, the server must
+    /// authenticate as a user with write access to the range. Without read and
+    /// write timeouts in `options`, the server gives up on an unresponsive target
+    /// after 10 seconds.
+    pub fn with_migration_options(mut self, options: ClientOptions) -> Self {
+        self.migration_options = options;
+        self
+    }
+
+    /// Store the ranges moved to other servers in the file at `path`, so that they
+    /// are still redirected after a restart.
+    ///
+    /// Migrations are refused without it.
+    pub fn with_migration_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.migration_file = Some(path.into());
+        self
+    }
+
+    /// A handle to stop the server once it runs.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     /// Create a listener bound to `addr`, and handle the connection received on this listener.
-    pub fn run(self, addr: SocketAddr) -> Result<()> {
-        let listener = TcpListener::bind(addr)?;
+    ///
+    /// It returns once a shutdown is asked for with a [`ShutdownHandle`], after the
+    /// connections in flight are done and the engine is flushed.
+    ///
+    /// `addr` is either a TCP socket address or a Unix domain socket path, see [`KvsAddr`].
+    pub fn run(self, addr: impl Into<KvsAddr>) -> Result<()> {
+        if self.event_loop && self.tls.is_some() {
+            return Err(KvsError::StringError(
+                "TLS is not supported by the event loop".to_owned(),
+            ));
+        }
+        // The event loop only serves the requests answered with a single response.
+        if self.event_loop && (self.cluster.is_some() || self.replica_of.is_some()) {
+            return Err(KvsError::StringError(
+                "replication is not supported by the event loop".to_owned(),
+            ));
+        }
+        let listener = Listener::bind(&addr.into())?;
+        self.shutdown.add_listener(listener.local_addr()?);
+        let tls = match self.tls {
+            Some(ref tls) => Some(tls.build()?),
+            None => None,
+        };
+
+        match self.cluster.clone() {
+            Some(_) if self.replica_of.is_some() => Err(KvsError::StringError(
+                "a cluster node cannot be a replica".to_owned(),
+            )),
+            Some(config) => {
+                // Every node publishes the writes it commits, whoever proposed them.
+                let applied = Replicated::new(self.store.clone(), None);
+                let cluster = Cluster::start(applied.clone(), config)?;
+                let raft = cluster.raft();
+                let store = Replicated::published_by(cluster, &applied);
+                let result = self.serve(listener, tls, store, Some(&raft));
+                raft.stop();
+                result
+            }
+            None => {
+                let primary = self.replica_of.as_ref().map(|(primary, _)| primary.clone());
+                let store = Replicated::new(self.store.clone(), primary);
+                if let Some((primary, options)) = self.replica_of.clone() {
+                    let store = store.clone();
+                    let shutdown = self.shutdown.clone();
+                    thread::spawn(move || replication::follow(store, primary, options, shutdown));
+                }
+                self.serve(listener, tls, store, None)
+            }
+        }
+    }
 
-        for stream in listener.incoming() {
+    /// Serve the connections of `listener` with `store` until a shutdown is asked for.
+    fn serve<S: KvsEngine>(
+        self,
+        listener: Listener,
+        tls: Option<Arc<ServerConfig>>,
+        store: Replicated<S>,
+        raft: Option<&Raft>,
+    ) -> Result<()> {
+        let local = listener.local_addr()?;
+        let store = Migrating::new(
+            store,
+            self.migration_options.clone(),
+            local,
+            self.migration_file.clone(),
+        )?;
+        if let Some(http_addr) = self.http_addr {
+            let http_listener = TcpListener::bind(http_addr)?;
+            info!("http gateway listening on {}", http_addr);
+            self.shutdown
+                .add_listener(KvsAddr::Tcp(http_listener.local_addr()?));
+            let store = store.clone();
+            let pool = self.pool.clone();
+            let acl = self.acl.clone();
+            let shutdown = self.shutdown.clone();
+            let connections = self.http_connections.clone();
+            thread::spawn(move || {
+                http::serve(http_listener, store, pool, acl, shutdown, connections)
+            });
+        }
+        if let Some(metrics_addr) = self.metrics_addr {
+            let metrics_listener = TcpListener::bind(metrics_addr)?;
+            info!("metrics listening on {}", metrics_addr);
+            self.shutdown
+                .add_listener(KvsAddr::Tcp(metrics_listener.local_addr()?));
+            let shutdown = self.shutdown.clone();
+            let connections = self.http_connections.clone();
+            thread::spawn(move || http::serve_metrics(metrics_listener, shutdown, connections));
+        }
+
+        if self.event_loop {
+            let mut event_loop = EventLoop::new(
+                listener,
+                store.clone(),
+                self.pool.clone(),
+                self.acl.clone(),
+                self.stats.clone(),
+                self.limits,
+            )?;
+            event_loop.run(
+                &self.shutdown,
+                self.receiver.as_ref(),
+                self.shutdown_timeout,
+            )?;
+            info!("shutting down");
+            return store.flush();
+        }
+
+        loop {
+            let stream = listener.accept();
+            if self.shutdown.is_shutdown() {
+                break;
+            }
             if let Some(rx) = self.receiver.as_ref() {
                 if rx.try_recv().is_ok() {
                     break;
                 }
             };
 
-            let mut stream = stream?;
+            let mut stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    error!("accept error: {}", err);
+                    continue;
+                }
+            };
+            let peer = stream.peer();
+            if self.limits.is_full(self.connections.len()) {
+                warn!("refusing connection from {}: too many connections", peer);
+                if tls.is_none() {
+                    refuse(&mut stream);
+                }
+                continue;
+            }
 
-            info!("connection from {:?}", stream.peer_addr()?);
-            let kv_store = self.store.clone();
+            info!("connection from {}", peer);
+            if let Some(ref config) = tls {
+                stream = match stream.accept_tls(config.clone()) {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        error!("connection from {} failed: {}", peer, err);
+                        continue;
+                    }
+                };
+            }
+            let connection = match stream.try_clone_socket() {
+                Ok(socket) => self.connections.register(socket),
+                Err(err) => {
+                    error!("connection from {} failed: {}", peer, err);
+                    continue;
+                }
+            };
+            let kv_store = store.clone();
+            let mut session = Session::new(self.acl.clone(), self.stats.clone(), peer);
+            let limits = self.limits;
+            let shutdown = self.shutdown.clone();
+            let raft = raft.cloned();
             self.pool.spawn(move || {
-                let request = read_cmd(&mut stream).unwrap();
-                let response = process_cmd(kv_store, request).unwrap();
-                respond(&mut stream, response).unwrap();
+                match serve_connection(&mut stream, &kv_store, &mut session, limits) {
+                    Ok(Served::Closed) => {}
+                    // Replicas are followed for as long as they run, away from the pool.
+                    Ok(Served::Replica) => {
+                        thread::spawn(move || {
+                            let _connection = connection;
+                            info!("replica {} connected", session.peer);
+                            let result = replication::serve_replica(
+                                kv_store.engine(),
+                                &mut stream,
+                                &shutdown,
+                            );
+                            if let Err(err) = result {
+                                error!("replica {} failed: {}", session.peer, err);
+                            }
+                        });
+                    }
+                    // So are the other nodes of the cluster.
+                    Ok(Served::Peer) => match raft {
+                        Some(raft) => {
+                            thread::spawn(move || {
+                                let _connection = connection;
+                                if let Err(err) = raft.serve_peer(stream) {
+                                    error!("node {} failed: {}", session.peer, err);
+                                }
+                            });
+                        }
+                        None => {
+                            let response =
+                                KvsError::InvalidRequest("Not a cluster node".to_owned());
+                            let _ = respond(&mut stream, response.into());
+                        }
+                    },
+                    Err(err) => error!("connection from {} failed: {}", session.peer, err),
+                }
             })
         }
 
-        Ok(())
+        info!("shutting down");
+        self.connections.close_all();
+        self.http_connections.close_all();
+        let deadline = Instant::now() + self.shutdown_timeout;
+        let running = self.connections.wait(deadline) + self.http_connections.wait(deadline);
+        if running > 0 {
+            warn!(
+                "{} connections still running after {:?}",
+                running, self.shutdown_timeout
+            );
+        }
+        store.flush()
     }
 }
 
-fn read_cmd(stream: &mut TcpStream) -> Result<Request> {
-    let mut de = serde_json::Deserializer::from_reader(stream);
-    let request = Request::deserialize(&mut de)?;
-    Ok(request)
+/// How [`serve_connection`] left a connection.
+enum Served {
+    /// The client is gone, or the connection was closed.
+    Closed,
+    /// The client is a replica, asking for the writes of the server.
+    Replica,
+    /// The client is another node of the cluster, talking Raft.
+    Peer,
 }
 
-fn process_cmd(kv_store: impl KvsEngine, msg: Request) -> Result<Response> {
-    let response = match msg {
-        Request::Set { ref key, ref value } => {
-            kv_store.set(key.to_owned(), value.to_owned())?;
-            Response::Ok(None)
+/// Answer the requests of a client until it closes the connection, sends a
+/// malformed request or a timeout closes it, or asks to replicate the server or
+/// to talk Raft.
+fn serve_connection(
+    stream: &mut Stream,
+    kv_store: &Migrating<impl KvsEngine>,
+    session: &mut Session,
+    limits: Limits,
+) -> Result<Served> {
+    stream.set_write_timeout(limits.write_timeout)?;
+    loop {
+        let mut reader = TimedReader::new(stream, &limits)?;
+        let request = match read_cmd(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => return Ok(Served::Closed),
+            Err(ref err) if is_timeout(err) => {
+                let timeout = if reader.started { "read" } else { "idle" };
+                warn!(
+                    "closing connection from {}: {} timeout",
+                    session.peer, timeout
+                );
+                return Ok(Served::Closed);
+            }
+            Err(err) => match session.reject(err) {
+                // The rest of the stream cannot be trusted, close the connection once answered.
+                Some(response) => {
+                    respond(stream, response)?;
+                    stream.linger();
+                    return Ok(Served::Closed);
+                }
+                None => return Ok(Served::Closed),
+            },
+        };
+        let served = match request {
+            Request::Replicate => Served::Replica,
+            Request::Raft => Served::Peer,
+            Request::Migrate { .. } => {
+                let running = Running::start(&request);
+                let result = session
+                    .authorize(&request)
+                    .and_then(|_| migrate(kv_store, request));
+                let ok = result.is_ok();
+                let response = result.unwrap_or_else(Response::from);
+                running.finish(session, &response, ok);
+                respond(stream, response)?;
+                continue;
+            }
+            request => {
+                let response = process_cmd(kv_store, session, request);
+                match respond(stream, response) {
+                    Err(ref err) if is_timeout(err) => {
+                        warn!("closing connection from {}: write timeout", session.peer);
+                        return Ok(Served::Closed);
+                    }
+                    result => result?,
+                }
+                continue;
+            }
+        };
+        match session.authorize(&request) {
+            Ok(()) => return Ok(served),
+            Err(err) => respond(stream, err.into())?,
+        }
+    }
+}
+
+/// Run a migration request.
+fn migrate(kv_store: &Migrating<impl KvsEngine>, request: Request) -> Result<Response> {
+    if let Request::Migrate { start, end, target } = request {
+        let copied = kv_store.migrate(KeyRange { start, end }, target.parse()?)?;
+        return Ok(Response::Ok(Some(copied.to_string())));
+    }
+    unreachable!()
+}
+
+/// Tell a client the server is too busy to serve it, as far as it can be done without blocking.
+fn refuse(stream: &mut Stream) {
+    let response = KvsError::ServerBusy("Too many connections".to_owned()).into();
+    let _ = stream.set_write_timeout(Some(REFUSE_TIMEOUT));
+    let _ = respond(stream, response);
+}
+
+/// Reads a request from a stream, waiting at most the idle timeout for its
+/// first byte, and the read timeout for the next ones.
+struct TimedReader<'a> {
+    stream: &'a mut Stream,
+    read_timeout: Option<Duration>,
+    started: bool,
+}
+
+impl<'a> TimedReader<'a> {
+    fn new(stream: &'a mut Stream, limits: &Limits) -> io::Result<Self> {
+        stream.set_read_timeout(limits.idle_timeout)?;
+        Ok(TimedReader {
+            stream,
+            read_timeout: limits.read_timeout,
+            started: false,
+        })
+    }
+}
+
+impl Read for TimedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stream.read(buf)?;
+        if !self.started && n > 0 {
+            self.started = true;
+            self.stream.set_read_timeout(self.read_timeout)?;
+        }
+        Ok(n)
+    }
+}
+
+/// The state of a client connection.
+pub(crate) struct Session {
+    acl: Option<Arc<Acl>>,
+    user: Option<String>,
+    stats: Arc<Stats>,
+    /// The remote end of the connection, for logging.
+    pub(crate) peer: String,
+}
+
+impl Session {
+    pub(crate) fn new(acl: Option<Arc<Acl>>, stats: Arc<Stats>, peer: String) -> Self {
+        METRICS.connection_opened();
+        Session {
+            acl,
+            user: None,
+            stats,
+            peer,
+        }
+    }
+
+    /// Handle a request that could not be read, returning the response to send if any.
+    ///
+    /// Malformed requests are answered with an `InvalidRequest` error, while
+    /// the connection is just closed on I/O failures.
+    pub(crate) fn reject(&self, err: KvsError) -> Option<Response> {
+        match err {
+            KvsError::SerdeJsonError(err) => {
+                warn!("invalid request from {}: {}", self.peer, err);
+                Some(KvsError::InvalidRequest(err.to_string()).into())
+            }
+            KvsError::InvalidRequest(message) => {
+                warn!("invalid request from {}: {}", self.peer, message);
+                Some(KvsError::InvalidRequest(message).into())
+            }
+            err => {
+                error!("connection from {} failed: {}", self.peer, err);
+                None
+            }
+        }
+    }
+
+    fn authenticate(&mut self, user: String, token: &str) -> Result<()> {
+        match self.acl {
+            Some(ref acl) if !acl.authenticate(&user, token) => {
+                warn!("authentication failed for user {} from {}", user, self.peer);
+                self.user = None;
+                Err(KvsError::Unauthorized("Authentication failed".to_owned()))
+            }
+            _ => {
+                self.user = Some(user);
+                Ok(())
+            }
+        }
+    }
+
+    /// Check the connection may run `request`.
+    fn authorize(&self, request: &Request) -> Result<()> {
+        let acl = match self.acl {
+            Some(ref acl) => acl,
+            // Removing, moving or streaming every key is too much to allow to
+            // anyone who can connect.
+            None => {
+                return match request {
+                    Request::FlushAll
+                    | Request::Migrate { .. }
+                    | Request::Replicate
+                    | Request::Raft => Err(KvsError::Unauthorized(format!(
+                        "{} needs an ACL granting admin access",
+                        COMMANDS[request.command()]
+                    ))),
+                    _ => Ok(()),
+                }
+            }
+        };
+        let all = String::new();
+        let (keys, access): (Vec<&String>, _) = match request {
+            Request::Auth { .. } | Request::Ping => return Ok(()),
+            // Replicas and cluster nodes read and write every key.
+            Request::Replicate
+            | Request::Raft
+            | Request::Migrate { .. }
+            | Request::Compact
+            | Request::FlushAll
+            | Request::SlowLog => (vec![&all], Access::Admin),
+            Request::Info | Request::Stats => (vec![&all], Access::Read),
+            Request::Get { key } => (vec![key], Access::Read),
+            Request::Set { key, .. } | Request::Remove { key } => (vec![key], Access::Write),
+            Request::MGet { keys } => (keys.iter().collect(), Access::Read),
+            Request::MSet { pairs } => (pairs.iter().map(|(key, _)| key).collect(), Access::Write),
+            Request::MDel { keys } => (keys.iter().collect(), Access::Write),
+        };
+        let user = match self.user {
+            Some(ref user) => user,
+            None => return Err(KvsError::Unauthorized("Authentication required".to_owned())),
+        };
+        match keys
+            .into_iter()
+            .find(|key| !acl.is_allowed(user, key, access))
+        {
+            Some(key) => Err(KvsError::Unauthorized(format!(
+                "User {} has no {:?} access to {}",
+                user, access, key
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        METRICS.connection_closed();
+    }
+}
+
+/// Read the next request, or `None` once the client closed the connection.
+///
+/// Requests larger than `MAX_MESSAGE_SIZE` are refused rather than buffered.
+fn read_cmd(stream: &mut impl Read) -> Result<Option<Request>> {
+    let mut limited = stream.take(MAX_MESSAGE_SIZE as u64);
+    let mut de = serde_json::Deserializer::from_reader(&mut limited);
+    let result = Request::deserialize(&mut de);
+    match result {
+        Ok(request) => Ok(Some(request)),
+        Err(_) if limited.limit() == 0 => Err(KvsError::InvalidRequest(format!(
+            "request larger than {} bytes",
+            MAX_MESSAGE_SIZE
+        ))),
+        Err(err) if err.is_eof() => Ok(None),
+        // TLS clients may close the connection without a close_notify alert.
+        Err(err) if err.io_error_kind() == Some(io::ErrorKind::UnexpectedEof) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Run `msg`, turning any failure into an error response.
+pub(crate) fn process_cmd(
+    kv_store: &impl KvsEngine,
+    session: &mut Session,
+    msg: Request,
+) -> Response {
+    let running = Running::start(&msg);
+    let result = session.authorize(&msg).and_then(|_| match msg {
+        Request::Auth { user, ref token } => session
+            .authenticate(user, token)
+            .map(|_| Response::Ok(None)),
+        Request::Set { key, value } => kv_store.set(key, value).map(|_| Response::Ok(None)),
+        Request::Get { key } => kv_store.get(key).map(Response::Ok),
+        Request::Remove { key } => kv_store.remove(key).map(|_| Response::Ok(None)),
+        Request::MGet { keys } => kv_store.get_many(keys).map(Response::Values),
+        Request::MSet { pairs } => kv_store.set_many(pairs).map(|_| Response::Ok(None)),
+        Request::MDel { keys } => {
+            let removed = kv_store.remove_many(keys.clone())?;
+            let values = keys
+                .into_iter()
+                .zip(removed)
+                .map(|(key, removed)| if removed { Some(key) } else { None })
+                .collect();
+            Ok(Response::Values(values))
+        }
+        Request::Info => info(kv_store).map(|info| Response::Ok(Some(info))),
+        Request::Stats => Ok(Response::Ok(Some(session.stats.report()))),
+        Request::Compact => kv_store.compact().map(|_| Response::Ok(None)),
+        Request::Ping => Ok(Response::Ok(Some("PONG".to_owned()))),
+        Request::FlushAll => {
+            warn!("flushing every key for {}", session.peer);
+            kv_store.clear().map(|_| Response::Ok(None))
+        }
+        Request::SlowLog => Ok(Response::SlowLog(session.stats.slowlog.queries())),
+        Request::Replicate | Request::Raft | Request::Migrate { .. } => Err(
+            KvsError::InvalidRequest("Only the threaded server serves this request".to_owned()),
+        ),
+    });
+    // Missing keys and redirects are answers, not failures of the server.
+    let failed = matches!(
+        result,
+        Err(ref err) if !matches!(err, KvsError::KeyNotFound | KvsError::Redirect(_))
+    );
+    let response = match result {
+        Ok(response) => response,
+        Err(err) => {
+            match err {
+                KvsError::KeyNotFound | KvsError::Unauthorized(_) | KvsError::Redirect(_) => {}
+                // A busy server, e.g. without a leader for now, is expected to recover.
+                KvsError::ServerBusy(_) => warn!("request from {} refused: {}", session.peer, err),
+                _ => error!("request from {} failed: {}", session.peer, err),
+            }
+            err.into()
         }
-        Request::Get { ref key } => match kv_store.get(key.to_owned())? {
-            Some(value) => Response::Ok(Some(value)),
-            None => Response::Ok(None),
-        },
-        Request::Remove { ref key } => match kv_store.remove(key.to_owned()) {
-            Err(_) => Response::Err("Key not found".to_owned()),
-            Ok(_) => Response::Ok(None),
-        },
     };
-    Ok(response)
+    running.finish(session, &response, !failed);
+    response
+}
+
+/// A request being run, counted once answered.
+struct Running {
+    request: RequestSummary,
+    started: Instant,
+    compactions: u64,
+}
+
+impl Running {
+    fn start(request: &Request) -> Self {
+        Running {
+            request: RequestSummary::new(request),
+            started: Instant::now(),
+            compactions: METRICS.compaction_mark(),
+        }
+    }
+
+    /// Count the request, which failed unless `ok`, and log it if it was slow.
+    fn finish(self, session: &Session, response: &Response, ok: bool) {
+        let duration = self.started.elapsed();
+        let command = self.request.command;
+        session.stats.record(command, ok);
+        METRICS.request(command, duration, ok);
+        if session.stats.slowlog.is_slow(duration) {
+            let compacting = METRICS.compacted_since(self.compactions);
+            let query =
+                SlowQuery::new(&self.request, response, &session.peer, duration, compacting);
+            debug!(
+                "slow {} request from {}: {:?}",
+                COMMANDS[command], session.peer, duration
+            );
+            session.stats.slowlog.push(query);
+        }
+    }
+}
+
+/// Describe `kv_store` as `name:value` lines.
+fn info(kv_store: &impl KvsEngine) -> Result<String> {
+    Ok(format!(
+        "engine:{}\nkeys:{}\ndisk_size:{}",
+        kv_store.name(),
+        kv_store.key_count()?,
+        kv_store.disk_size()?
+    ))
 }
 
-fn respond(mut stream: &mut TcpStream, resp: Response) -> Result<()> {
-    serde_json::to_writer(&mut stream, &resp)?;
+fn respond(stream: &mut impl Write, resp: Response) -> Result<()> {
+    stream.write_all(&serde_json::to_vec(&resp)?)?;
     stream.flush()?;
     Ok(())
 }
@@ -0,0 +1,267 @@
+use crate::client::ClientOptions;
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::pool::{PoolOptions, PooledKvsClient};
+use crate::transport::KvsAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::thread;
+
+const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// A client spreading the keys over many kvs servers, each holding a shard of the data.
+///
+/// Keys are placed with a consistent-hash ring: each server gets many points on
+/// the ring (its virtual nodes), and a key goes to the server of the first point
+/// at or after the hash of the key. Adding or removing a server only moves the
+/// keys of the ranges next to its points, the others stay where they are.
+///
+/// The data of the moved keys is not copied over: a key that moves to another
+/// server is missing there until set again.
+///
+/// Multi-key requests are split by server, and sent to the servers in parallel.
+/// Like [`PooledKvsClient`], it can be shared by many threads.
+///
+/// # Examples
+/// ```no_run
+/// # use kvs::{ClientOptions, KvsAddr, PoolOptions, Result, ShardedKvsClient};
+/// #
+/// # fn main() -> Result<()> {
+/// let addrs: Vec<KvsAddr> = vec!["127.0.0.1:4000".parse()?, "127.0.0.1:4001".parse()?];
+/// let mut client = ShardedKvsClient::new(addrs, ClientOptions::default(), PoolOptions::default());
+/// client.mset(vec![
+///     ("key1".to_owned(), "value1".to_owned()),
+///     ("key2".to_owned(), "value2".to_owned()),
+/// ])?;
+///
+/// // Only the keys in the ranges taken by the new server move to it.
+/// client.add_node("127.0.0.1:4002".parse::<KvsAddr>()?);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct ShardedKvsClient {
+    ring: Ring,
+    shards: HashMap<KvsAddr, PooledKvsClient>,
+    client: ClientOptions,
+    pool: PoolOptions,
+}
+
+impl ShardedKvsClient {
+    /// Create a client to the servers on `addrs`, connecting to each of them with
+    /// `client`, and keeping a pool of connections with `options`.
+    pub fn new<A: Into<KvsAddr>>(
+        addrs: impl IntoIterator<Item = A>,
+        client: ClientOptions,
+        options: PoolOptions,
+    ) -> Self {
+        let mut sharded = ShardedKvsClient {
+            ring: Ring::new(DEFAULT_VIRTUAL_NODES),
+            shards: HashMap::new(),
+            client,
+            pool: options,
+        };
+        for addr in addrs {
+            sharded.add_node(addr);
+        }
+        sharded
+    }
+
+    /// Give each server `count` points on the ring. Defaults to 160.
+    ///
+    /// More points spread the keys more evenly, at the cost of a larger ring.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `count` is 0.
+    pub fn with_virtual_nodes(mut self, count: usize) -> Self {
+        assert!(count > 0, "a server needs at least one virtual node");
+        let mut ring = Ring::new(count);
+        for addr in self.shards.keys() {
+            ring.add(addr);
+        }
+        self.ring = ring;
+        self
+    }
+
+    /// Add the server on `addr`, taking over the keys of the ranges before its points.
+    pub fn add_node(&mut self, addr: impl Into<KvsAddr>) {
+        let addr = addr.into();
+        if self.shards.contains_key(&addr) {
+            return;
+        }
+        self.ring.add(&addr);
+        let shard = PooledKvsClient::new(addr.clone(), self.client.clone(), self.pool.clone());
+        self.shards.insert(addr, shard);
+    }
+
+    /// Remove the server on `addr`, its keys going to the next servers on the ring.
+    /// Returns whether the server was part of the ring.
+    pub fn remove_node(&mut self, addr: &KvsAddr) -> bool {
+        self.ring.remove(addr);
+        self.shards.remove(addr).is_some()
+    }
+
+    /// The server `key` goes to, or `None` if there is no server.
+    pub fn node_for(&self, key: &str) -> Option<&KvsAddr> {
+        self.ring.node_for(key)
+    }
+
+    /// Send to the server of the key to insert a key/value, and wait for it to respond.
+    pub fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key)?.set(key, value)
+    }
+
+    /// Send to the server of the key to get its value, and wait for it to respond.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key)?.get(key)
+    }
+
+    /// Send to the server of the key to remove it, and wait for it to respond.
+    pub fn remove(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key)?.remove(key)
+    }
+
+    /// Get the values of many keys, with a request to each of their servers.
+    /// The value of a key that does not exist is None.
+    pub fn mget(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.fan_out(keys, |shard, keys| shard.mget(keys))
+    }
+
+    /// Insert many key/values, with a request to each of their servers.
+    ///
+    /// The servers apply their part independently: if one of them fails, the
+    /// others may still have applied theirs.
+    pub fn mset(&self, pairs: Vec<(String, String)>) -> Result<()> {
+        let mut shards: HashMap<&KvsAddr, Vec<(String, String)>> = HashMap::new();
+        for (key, value) in pairs {
+            shards
+                .entry(self.node(&key)?)
+                .or_default()
+                .push((key, value));
+        }
+        thread::scope(|scope| {
+            let handles: Vec<_> = shards
+                .into_iter()
+                .map(|(addr, pairs)| {
+                    let shard = &self.shards[addr];
+                    scope.spawn(move || shard.mset(pairs))
+                })
+                .collect();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().unwrap())
+        })
+    }
+
+    /// Remove many keys, with a request to each of their servers, returning each
+    /// removed key in the order of `keys`, or None for the keys that did not exist.
+    pub fn mdel(&self, keys: Vec<String>) -> Result<Vec<Option<String>>> {
+        self.fan_out(keys, |shard, keys| shard.mdel(keys))
+    }
+
+    /// Run `request` with the keys of each server in parallel, putting the
+    /// results back in the order of `keys`.
+    fn fan_out(
+        &self,
+        keys: Vec<String>,
+        request: impl Fn(&PooledKvsClient, Vec<String>) -> Result<Vec<Option<String>>> + Sync,
+    ) -> Result<Vec<Option<String>>> {
+        let len = keys.len();
+        let mut shards: HashMap<&KvsAddr, (Vec<usize>, Vec<String>)> = HashMap::new();
+        for (index, key) in keys.into_iter().enumerate() {
+            let (indexes, keys) = shards.entry(self.node(&key)?).or_default();
+            indexes.push(index);
+            keys.push(key);
+        }
+        let request = &request;
+        let mut values = vec![None; len];
+        thread::scope(|scope| {
+            let handles: Vec<_> = shards
+                .into_iter()
+                .map(|(addr, (indexes, keys))| {
+                    let shard = &self.shards[addr];
+                    (indexes, scope.spawn(move || request(shard, keys)))
+                })
+                .collect();
+            for (indexes, handle) in handles {
+                for (index, value) in indexes.into_iter().zip(handle.join().unwrap()?) {
+                    values[index] = value;
+                }
+            }
+            Ok(values)
+        })
+    }
+
+    fn node(&self, key: &str) -> Result<&KvsAddr> {
+        self.node_for(key)
+            .ok_or_else(|| KvsError::StringError("no server to send the key to".to_owned()))
+    }
+
+    fn shard(&self, key: &str) -> Result<&PooledKvsClient> {
+        Ok(&self.shards[self.node(key)?])
+    }
+}
+
+/// A consistent-hash ring of servers.
+#[derive(Clone)]
+struct Ring {
+    virtual_nodes: usize,
+    points: BTreeMap<u64, KvsAddr>,
+}
+
+impl Ring {
+    fn new(virtual_nodes: usize) -> Self {
+        Ring {
+            virtual_nodes,
+            points: BTreeMap::new(),
+        }
+    }
+
+    /// The points of `addr` on the ring.
+    fn points(&self, addr: &KvsAddr) -> impl Iterator<Item = u64> {
+        let addr = addr.to_string();
+        (0..self.virtual_nodes).map(move |i| hash(format!("{}#{}", addr, i).as_bytes()))
+    }
+
+    fn add(&mut self, addr: &KvsAddr) {
+        for point in self.points(addr).collect::<Vec<_>>() {
+            self.points.insert(point, addr.clone());
+        }
+    }
+
+    fn remove(&mut self, addr: &KvsAddr) {
+        for point in self.points(addr).collect::<Vec<_>>() {
+            // Leave the points of other servers that happen to collide.
+            if self.points.get(&point) == Some(addr) {
+                self.points.remove(&point);
+            }
+        }
+    }
+
+    fn node_for(&self, key: &str) -> Option<&KvsAddr> {
+        let hash = hash(key.as_bytes());
+        self.points
+            .range(hash..)
+            .next()
+            .or_else(|| self.points.iter().next())
+            .map(|(_, addr)| addr)
+    }
+}
+
+/// Hash `bytes` with 64-bit FNV-1a.
+///
+/// FNV-1a barely changes the high bits for inputs that only differ at their end,
+/// like `key1` and `key2`, which would put them next to each other on the ring:
+/// the hash is finished with the avalanche step of MurmurHash3 to spread them.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    let mut hash = bytes.iter().fold(OFFSET_BASIS, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(PRIME)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
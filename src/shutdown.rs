@@ -0,0 +1,162 @@
+use crate::transport::{KvsAddr, Stream};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Instant;
+use tokio::sync::watch;
+
+/// A handle to stop a running [`KvsServer`](crate::KvsServer).
+///
+/// It is obtained with [`KvsServer::shutdown_handle`](crate::KvsServer::shutdown_handle)
+/// before the server runs, and may be cloned and sent to other threads, e.g. a signal handler.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    requested: AtomicBool,
+    listeners: Mutex<Vec<KvsAddr>>,
+    signal: watch::Sender<bool>,
+}
+
+impl Default for Inner {
+    fn default() -> Self {
+        Inner {
+            requested: AtomicBool::new(false),
+            listeners: Mutex::new(Vec::new()),
+            signal: watch::Sender::new(false),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// Ask the server to stop.
+    ///
+    /// The server stops accepting connections, waits for the requests in flight and
+    /// flushes its engine before [`KvsServer::run`](crate::KvsServer::run) returns.
+    /// This method does not wait for it.
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        self.inner.signal.send_replace(true);
+        let listeners = self.inner.listeners.lock().unwrap();
+        for addr in listeners.iter() {
+            // Wake up the thread blocked accepting connections on `addr`.
+            if let Err(err) = Stream::connect(addr) {
+                warn!("unable to wake up the listener on {}: {}", addr, err);
+            }
+        }
+    }
+
+    /// Whether a shutdown was asked for.
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// Subscribe to the shutdown, for async servers to wait for it with [`requested`].
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inner.signal.subscribe()
+    }
+
+    /// Register a listener bound to `addr`, to be woken up on shutdown.
+    pub(crate) fn add_listener(&self, addr: KvsAddr) {
+        let addr = match addr {
+            KvsAddr::Tcp(addr) if addr.ip().is_unspecified() => {
+                let ip = match addr.ip() {
+                    IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                };
+                KvsAddr::Tcp(SocketAddr::new(ip, addr.port()))
+            }
+            addr => addr,
+        };
+        self.inner.listeners.lock().unwrap().push(addr);
+        // A shutdown asked for before the listener was registered has nobody to wake up.
+        if self.is_shutdown() {
+            self.shutdown();
+        }
+    }
+}
+
+/// Wait until a shutdown is asked for on `signal`.
+pub(crate) async fn requested(signal: &mut watch::Receiver<bool>) {
+    if signal.wait_for(|&requested| requested).await.is_err() {
+        // The handle is gone, nobody can ask for a shutdown any more.
+        std::future::pending::<()>().await;
+    }
+}
+
+/// The connections a server is handling.
+///
+/// On shutdown, their read side is closed: a connection waiting for its next
+/// request sees the end of the stream, while a request being processed still
+/// gets its response.
+#[derive(Default)]
+pub(crate) struct Connections {
+    state: Mutex<State>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    sockets: HashMap<u64, Stream>,
+}
+
+impl Connections {
+    /// Track the connection on `socket` until the returned guard is dropped.
+    pub(crate) fn register(self: &Arc<Self>, socket: Stream) -> Connection {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.sockets.insert(id, socket);
+        Connection {
+            id,
+            connections: self.clone(),
+        }
+    }
+
+    /// Stop reading requests from all the connections.
+    pub(crate) fn close_all(&self) {
+        let state = self.state.lock().unwrap();
+        for socket in state.sockets.values() {
+            // The connection may already be closed by the client.
+            let _ = socket.shutdown_read();
+        }
+    }
+
+    /// The number of connections open.
+    pub(crate) fn len(&self) -> usize {
+        self.state.lock().unwrap().sockets.len()
+    }
+
+    /// Wait for all the connections to finish, at most until `deadline`.
+    ///
+    /// Return the number of connections still running.
+    pub(crate) fn wait(&self, deadline: Instant) -> usize {
+        let mut state = self.state.lock().unwrap();
+        while !state.sockets.is_empty() {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            state = self.closed.wait_timeout(state, deadline - now).unwrap().0;
+        }
+        state.sockets.len()
+    }
+}
+
+/// A connection tracked by [`Connections`].
+pub(crate) struct Connection {
+    id: u64,
+    connections: Arc<Connections>,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let mut state = self.connections.state.lock().unwrap();
+        state.sockets.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
@@ -0,0 +1,134 @@
+use crate::network::{Request, Response, COMMANDS};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_THRESHOLD: Duration = Duration::from_millis(10);
+const DEFAULT_CAPACITY: usize = 128;
+
+/// A request that took longer than the slow-query threshold of the server.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct SlowQuery {
+    /// The command of the request, e.g. `set` or `mget`.
+    pub command: String,
+    /// The key of the request, or its first key for the multi-key requests.
+    pub key: Option<String>,
+    /// The number of bytes of the values sent or returned.
+    pub value_size: usize,
+    /// The client that sent the request.
+    pub peer: String,
+    /// How long the request took to run.
+    pub duration: Duration,
+    /// Whether a compaction of the engine ran meanwhile.
+    pub compacting: bool,
+    /// When the request completed, in seconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// The last slow requests of a server, oldest first.
+pub(crate) struct SlowLog {
+    threshold: Duration,
+    capacity: usize,
+    queries: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl Default for SlowLog {
+    fn default() -> Self {
+        SlowLog::new(DEFAULT_THRESHOLD, DEFAULT_CAPACITY)
+    }
+}
+
+impl SlowLog {
+    /// Keep the last `capacity` requests taking longer than `threshold`.
+    pub(crate) fn new(threshold: Duration, capacity: usize) -> Self {
+        SlowLog {
+            threshold,
+            capacity,
+            queries: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    pub(crate) fn is_slow(&self, duration: Duration) -> bool {
+        self.capacity > 0 && duration > self.threshold
+    }
+
+    pub(crate) fn push(&self, query: SlowQuery) {
+        let mut queries = self.queries.lock().unwrap();
+        if queries.len() == self.capacity {
+            queries.pop_front();
+        }
+        queries.push_back(query);
+    }
+
+    pub(crate) fn queries(&self) -> Vec<SlowQuery> {
+        self.queries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl SlowQuery {
+    /// Describe `request` of `peer`, answered with `response`.
+    pub(crate) fn new(
+        request: &RequestSummary,
+        response: &Response,
+        peer: &str,
+        duration: Duration,
+        compacting: bool,
+    ) -> Self {
+        let returned = match response {
+            Response::Ok(Some(value)) if request.returns_values => value.len(),
+            Response::Values(values) if request.returns_values => {
+                values.iter().flatten().map(String::len).sum()
+            }
+            _ => 0,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs())
+            .unwrap_or_default();
+        SlowQuery {
+            command: COMMANDS[request.command].to_owned(),
+            key: request.key.clone(),
+            value_size: request.value_size + returned,
+            peer: peer.to_owned(),
+            duration,
+            compacting,
+            timestamp,
+        }
+    }
+}
+
+/// What the slow-query log keeps of a request, taken before it runs.
+pub(crate) struct RequestSummary {
+    /// The index of the command in [`COMMANDS`](crate::network::COMMANDS).
+    pub(crate) command: usize,
+    key: Option<String>,
+    value_size: usize,
+    /// Whether the values of the response come from the engine.
+    returns_values: bool,
+}
+
+impl RequestSummary {
+    pub(crate) fn new(request: &Request) -> Self {
+        let (key, value_size, returns_values) = match request {
+            Request::Set { key, value } => (Some(key), value.len(), false),
+            Request::Get { key } => (Some(key), 0, true),
+            Request::Remove { key } => (Some(key), 0, false),
+            Request::MGet { keys } => (keys.first(), 0, true),
+            Request::MSet { pairs } => (
+                pairs.first().map(|(key, _)| key),
+                pairs.iter().map(|(_, value)| value.len()).sum(),
+                false,
+            ),
+            Request::MDel { keys } => (keys.first(), 0, false),
+            Request::Migrate { start, .. } => (Some(start), 0, false),
+            _ => (None, 0, false),
+        };
+        RequestSummary {
+            command: request.command(),
+            key: key.cloned(),
+            value_size,
+            returns_values,
+        }
+    }
+}
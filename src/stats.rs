@@ -0,0 +1,64 @@
+use crate::network::COMMANDS;
+use crate::slowlog::SlowLog;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// The counters of a server, reported by `Request::Stats`, and its slow requests.
+pub(crate) struct Stats {
+    started: Instant,
+    /// The requests of each command, indexed as [`COMMANDS`].
+    requests: [AtomicU64; COMMANDS.len()],
+    errors: AtomicU64,
+    pub(crate) slowlog: SlowLog,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Stats::new(SlowLog::default())
+    }
+}
+
+impl Stats {
+    pub(crate) fn new(slowlog: SlowLog) -> Self {
+        Stats {
+            started: Instant::now(),
+            requests: Default::default(),
+            errors: AtomicU64::new(0),
+            slowlog,
+        }
+    }
+
+    /// Count a request for the command `command` indexes, which failed unless `ok`.
+    pub(crate) fn record(&self, command: usize, ok: bool) {
+        self.requests[command].fetch_add(1, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// The counters as `name:value` lines.
+    pub(crate) fn report(&self) -> String {
+        let requests: Vec<u64> = self
+            .requests
+            .iter()
+            .map(|count| count.load(Ordering::Relaxed))
+            .collect();
+        let mut report = format!(
+            "uptime_seconds:{}\nrequests:{}\nerrors:{}",
+            self.uptime().as_secs(),
+            requests.iter().sum::<u64>(),
+            self.errors.load(Ordering::Relaxed)
+        );
+        for (command, count) in COMMANDS.iter().zip(requests) {
+            if count > 0 {
+                write!(report, "\nrequests_{}:{}", command, count).unwrap();
+            }
+        }
+        report
+    }
+}
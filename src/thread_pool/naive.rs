@@ -1,3 +1,4 @@
+use crate::metrics::METRICS;
 use crate::thread_pool::ThreadPool;
 use crate::Result;
 use std::thread;
@@ -18,6 +19,6 @@ impl ThreadPool for NaiveThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        thread::spawn(move || job());
+        thread::spawn(METRICS.queue(job));
     }
 }
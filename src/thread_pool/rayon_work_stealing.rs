@@ -1,4 +1,5 @@
 use crate::engine::Result;
+use crate::metrics::METRICS;
 use crate::thread_pool::ThreadPool;
 
 /// Using thre `ThreadPool` type from the [`rayon`](https://docs.rs/rayon/1.5.0/rayon/) crate.
@@ -22,6 +23,6 @@ impl ThreadPool for RayonThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        self.pool.spawn(job);
+        self.pool.spawn(METRICS.queue(job));
     }
 }
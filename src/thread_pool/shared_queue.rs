@@ -1,5 +1,6 @@
 use super::ThreadPool;
 use crate::engine::Result;
+use crate::metrics::METRICS;
 use std::panic;
 use std::sync::mpsc;
 use std::sync::Arc;
@@ -51,7 +52,7 @@ impl ThreadPool for SharedQueueThreadPool {
     where
         F: FnOnce() + Send + 'static,
     {
-        let job = Box::new(f);
+        let job = Box::new(METRICS.queue(f));
 
         self.sender.send(Message::NewJob(job)).unwrap();
     }
@@ -92,7 +93,7 @@ impl Worker {
                 Message::NewJob(job) => {
                     info!("Worker {} got a job; executing.", id);
 
-                    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| job()));
+                    let result = panic::catch_unwind(panic::AssertUnwindSafe(job));
                     if result.is_err() {
                         info!("Worker {} got an error", id)
                     }
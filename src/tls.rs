@@ -0,0 +1,138 @@
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::transport::KvsAddr;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// TLS settings of a [`KvsServer`](crate::KvsServer).
+///
+/// Certificates and keys are read from PEM files when the server starts.
+#[derive(Debug, Clone)]
+pub struct TlsServerConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+}
+
+impl TlsServerConfig {
+    /// Serve the certificate chain in `cert`, whose private key is in `key`.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        TlsServerConfig {
+            cert: cert.into(),
+            key: key.into(),
+            client_ca: None,
+        }
+    }
+
+    /// Require clients to present a certificate signed by one of the CAs in `ca` (mutual TLS).
+    pub fn with_client_ca(mut self, ca: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(ca.into());
+        self
+    }
+
+    pub(crate) fn build(&self) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder();
+        let builder = match self.client_ca {
+            Some(ref ca) => {
+                let roots = Arc::new(load_roots(ca)?);
+                let verifier = WebPkiClientVerifier::builder(roots)
+                    .build()
+                    .map_err(|err| err.to_string())?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(&self.cert)?, load_key(&self.key)?)?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// TLS settings of a [`KvsClient`](crate::KvsClient).
+///
+/// The server certificate is verified against the CAs in a PEM bundle, and its
+/// name against the host the client connects to.
+#[derive(Debug, Clone)]
+pub struct TlsClientConfig {
+    ca: PathBuf,
+    domain: Option<String>,
+    identity: Option<(PathBuf, PathBuf)>,
+}
+
+impl TlsClientConfig {
+    /// Trust the server certificates signed by one of the CAs in `ca`.
+    pub fn new(ca: impl Into<PathBuf>) -> Self {
+        TlsClientConfig {
+            ca: ca.into(),
+            domain: None,
+            identity: None,
+        }
+    }
+
+    /// Verify the server certificate against `domain` instead of the IP address connected to.
+    ///
+    /// It is required when connecting over a Unix domain socket.
+    pub fn with_domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Authenticate to the server with the certificate chain in `cert`, whose private key is in `key`.
+    pub fn with_identity(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.identity = Some((cert.into(), key.into()));
+        self
+    }
+
+    pub(crate) fn build(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder().with_root_certificates(load_roots(&self.ca)?);
+        let config = match self.identity {
+            Some((ref cert, ref key)) => {
+                builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+
+    pub(crate) fn server_name(&self, addr: &KvsAddr) -> Result<ServerName<'static>> {
+        match (&self.domain, addr) {
+            (Some(domain), _) => ServerName::try_from(domain.to_owned())
+                .map_err(|_| KvsError::StringError(format!("invalid domain name: {}", domain))),
+            (None, KvsAddr::Tcp(addr)) => Ok(ServerName::IpAddress(addr.ip().into())),
+            (None, KvsAddr::Unix(_)) => Err(KvsError::StringError(
+                "a TLS domain is required for Unix domain sockets".to_owned(),
+            )),
+        }
+    }
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
+    if certs.is_empty() {
+        return Err(KvsError::StringError(format!(
+            "{}: no certificate found",
+            path.display()
+        )));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let key =
+        PrivateKeyDer::from_pem_file(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+    Ok(key)
+}
+
+fn load_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
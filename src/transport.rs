@@ -0,0 +1,441 @@
+use crate::engine::Result;
+use crate::error::KvsError;
+use crate::limits::LINGER;
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, ServerConfig, ServerConnection, StreamOwned};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// An address a kvs server listens on and a kvs client connects to.
+///
+/// It is parsed either from an `IP:PORT` pair, or from `unix:/path/to/socket`
+/// for a Unix domain socket.
+///
+/// # Examples
+/// ```
+/// # use kvs::KvsAddr;
+/// let addr: KvsAddr = "127.0.0.1:4000".parse().unwrap();
+/// assert_eq!(addr, KvsAddr::Tcp("127.0.0.1:4000".parse().unwrap()));
+///
+/// let addr: KvsAddr = "unix:/tmp/kvs.sock".parse().unwrap();
+/// assert_eq!(addr.to_string(), "unix:/tmp/kvs.sock");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum KvsAddr {
+    /// A TCP socket address.
+    Tcp(SocketAddr),
+    /// The path of a Unix domain socket.
+    Unix(PathBuf),
+}
+
+impl FromStr for KvsAddr {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err(KvsError::StringError(format!("invalid address: {}", s))),
+            Some(path) => Ok(KvsAddr::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(KvsAddr::Tcp)
+                .map_err(|_| KvsError::StringError(format!("invalid address: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for KvsAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvsAddr::Tcp(addr) => write!(f, "{}", addr),
+            KvsAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+impl From<SocketAddr> for KvsAddr {
+    fn from(addr: SocketAddr) -> KvsAddr {
+        KvsAddr::Tcp(addr)
+    }
+}
+
+impl From<&SocketAddr> for KvsAddr {
+    fn from(addr: &SocketAddr) -> KvsAddr {
+        KvsAddr::Tcp(*addr)
+    }
+}
+
+impl From<&KvsAddr> for KvsAddr {
+    fn from(addr: &KvsAddr) -> KvsAddr {
+        addr.clone()
+    }
+}
+
+/// A listening socket of any supported transport.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Bind to `addr`. A stale Unix socket file nobody listens on any more is replaced.
+    pub(crate) fn bind(addr: &KvsAddr) -> Result<Listener> {
+        match addr {
+            KvsAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            #[cfg(unix)]
+            KvsAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            KvsAddr::Unix(_) => Err(unsupported_unix_socket()),
+        }
+    }
+
+    /// Accept a new connection.
+    pub(crate) fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Tcp(listener) => listener.accept().map(|(stream, _)| Stream::Tcp(stream)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+
+    /// The address the listener is bound to, with the actual port if 0 was asked for.
+    pub(crate) fn local_addr(&self) -> Result<KvsAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(KvsAddr::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(listener) => match listener.local_addr()?.as_pathname() {
+                Some(path) => Ok(KvsAddr::Unix(path.to_owned())),
+                None => Err(KvsError::StringError(
+                    "unnamed Unix domain socket".to_owned(),
+                )),
+            },
+        }
+    }
+}
+
+/// A connected socket of any supported transport, optionally wrapped in TLS.
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    TlsServer(Box<StreamOwned<ServerConnection, Stream>>),
+    TlsClient(Box<StreamOwned<ClientConnection, Stream>>),
+}
+
+impl Stream {
+    /// Connect to `addr`.
+    pub(crate) fn connect(addr: &KvsAddr) -> Result<Stream> {
+        Stream::connect_timeout(addr, None)
+    }
+
+    /// Connect to `addr`, giving up after `timeout` for TCP.
+    pub(crate) fn connect_timeout(addr: &KvsAddr, timeout: Option<Duration>) -> Result<Stream> {
+        match (addr, timeout) {
+            (KvsAddr::Tcp(addr), Some(timeout)) => {
+                Ok(Stream::Tcp(TcpStream::connect_timeout(addr, timeout)?))
+            }
+            (KvsAddr::Tcp(addr), None) => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            #[cfg(unix)]
+            (KvsAddr::Unix(path), _) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            (KvsAddr::Unix(_), _) => Err(unsupported_unix_socket()),
+        }
+    }
+
+    /// Set the timeout of the reads on the underlying socket, `None` to block.
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            Stream::TlsServer(stream) => stream.sock.set_read_timeout(timeout),
+            Stream::TlsClient(stream) => stream.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// Set the timeout of the writes on the underlying socket, `None` to block.
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            Stream::TlsServer(stream) => stream.sock.set_write_timeout(timeout),
+            Stream::TlsClient(stream) => stream.sock.set_write_timeout(timeout),
+        }
+    }
+
+    /// Wrap an accepted connection in TLS. The handshake happens on the first read or write.
+    pub(crate) fn accept_tls(self, config: Arc<ServerConfig>) -> Result<Stream> {
+        let conn = ServerConnection::new(config)?;
+        Ok(Stream::TlsServer(Box::new(StreamOwned::new(conn, self))))
+    }
+
+    /// Wrap a connection to `name` in TLS, and complete the handshake.
+    pub(crate) fn connect_tls(
+        self,
+        config: Arc<ClientConfig>,
+        name: ServerName<'static>,
+    ) -> Result<Stream> {
+        let conn = ClientConnection::new(config, name)?;
+        let mut tls = StreamOwned::new(conn, self);
+        while tls.conn.is_handshaking() {
+            tls.conn.complete_io(&mut tls.sock)?;
+        }
+        Ok(Stream::TlsClient(Box::new(tls)))
+    }
+
+    /// Another handle to the underlying socket, without TLS.
+    pub(crate) fn try_clone_socket(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            Stream::TlsServer(stream) => stream.sock.try_clone_socket(),
+            Stream::TlsClient(stream) => stream.sock.try_clone_socket(),
+        }
+    }
+
+    /// Shut down the read half of the socket: pending and future reads see the end of the stream.
+    pub(crate) fn shutdown_read(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Read),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Read),
+            Stream::TlsServer(stream) => stream.sock.shutdown_read(),
+            Stream::TlsClient(stream) => stream.sock.shutdown_read(),
+        }
+    }
+
+    /// Shut down the write half of the socket, telling the remote end no more data comes.
+    pub(crate) fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Write),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.shutdown(Shutdown::Write),
+            Stream::TlsServer(stream) => stream.sock.shutdown_write(),
+            Stream::TlsClient(stream) => stream.sock.shutdown_write(),
+        }
+    }
+
+    /// Close the connection once the last response is sent, without reading
+    /// any more requests.
+    ///
+    /// Closing a socket with unread input resets the connection, which may
+    /// drop the response, so the input is read and discarded for a little while.
+    pub(crate) fn linger(&mut self) {
+        if self.shutdown_write().is_err() || self.set_read_timeout(Some(LINGER)).is_err() {
+            return;
+        }
+        let deadline = Instant::now() + LINGER;
+        let mut buf = [0; 4096];
+        while Instant::now() < deadline {
+            match self.read(&mut buf) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+        }
+    }
+
+    /// Whether an idle connection is still usable: the remote end has neither
+    /// closed it nor sent anything unasked for.
+    pub(crate) fn is_reusable(&self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let mut socket = match self.try_clone_socket() {
+            Ok(socket) => socket,
+            Err(_) => return false,
+        };
+        let reusable = match socket.read(&mut [0; 1]) {
+            Err(ref err) => err.kind() == io::ErrorKind::WouldBlock,
+            Ok(_) => false,
+        };
+        reusable && self.set_nonblocking(false).is_ok()
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Stream::TlsServer(stream) => stream.sock.set_nonblocking(nonblocking),
+            Stream::TlsClient(stream) => stream.sock.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// A printable description of the remote end, used for logging.
+    pub(crate) fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => match stream.peer_addr() {
+                Ok(addr) => addr.to_string(),
+                Err(_) => "unknown".to_owned(),
+            },
+            #[cfg(unix)]
+            Stream::Unix(_) => "unix socket".to_owned(),
+            Stream::TlsServer(stream) => stream.sock.peer(),
+            Stream::TlsClient(stream) => stream.sock.peer(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            Stream::TlsServer(stream) => stream.read(buf),
+            Stream::TlsClient(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            Stream::TlsServer(stream) => stream.write(buf),
+            Stream::TlsClient(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            Stream::TlsServer(stream) => stream.flush(),
+            Stream::TlsClient(stream) => stream.flush(),
+        }
+    }
+}
+
+/// A connected async socket of any supported transport, optionally wrapped in TLS.
+pub(crate) trait AsyncIo: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncIo for T {}
+
+pub(crate) type AsyncStream = Box<dyn AsyncIo>;
+
+/// An async listening socket of any supported transport.
+pub(crate) enum AsyncListener {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+impl AsyncListener {
+    /// Bind to `addr`, like [`Listener::bind`].
+    pub(crate) async fn bind(addr: &KvsAddr) -> Result<AsyncListener> {
+        match addr {
+            KvsAddr::Tcp(addr) => Ok(AsyncListener::Tcp(
+                tokio::net::TcpListener::bind(addr).await?,
+            )),
+            #[cfg(unix)]
+            KvsAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(AsyncListener::Unix(tokio::net::UnixListener::bind(path)?))
+            }
+            #[cfg(not(unix))]
+            KvsAddr::Unix(_) => Err(unsupported_unix_socket()),
+        }
+    }
+
+    /// Accept a new connection, along with a printable description of the remote end.
+    pub(crate) async fn accept(&self) -> io::Result<(AsyncStream, String)> {
+        match self {
+            AsyncListener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Box::new(stream), addr.to_string()))
+            }
+            #[cfg(unix)]
+            AsyncListener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), "unix socket".to_owned()))
+            }
+        }
+    }
+}
+
+/// Connect to `addr` asynchronously, giving up after `timeout`.
+pub(crate) async fn connect_async(
+    addr: &KvsAddr,
+    timeout: Option<Duration>,
+) -> Result<AsyncStream> {
+    match addr {
+        KvsAddr::Tcp(addr) => {
+            let connect = tokio::net::TcpStream::connect(addr);
+            let stream = with_timeout(timeout, "connect", connect).await?;
+            Ok(Box::new(stream))
+        }
+        #[cfg(unix)]
+        KvsAddr::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        #[cfg(not(unix))]
+        KvsAddr::Unix(_) => Err(unsupported_unix_socket()),
+    }
+}
+
+/// Run `future`, failing with a `TimedOut` error naming `what` if it takes longer than `timeout`.
+pub(crate) async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    what: &str,
+    future: impl std::future::Future<Output = io::Result<T>>,
+) -> io::Result<T> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("{} timeout", what),
+            )),
+        },
+        None => future.await,
+    }
+}
+
+/// Remove the socket file at `path` if nobody listens on it any more.
+///
+/// Anything at `path` other than a socket is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} exists and is not a socket", path.display()),
+        )
+        .into());
+    }
+    if UnixStream::connect(path).is_err() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn unsupported_unix_socket() -> KvsError {
+    KvsError::StringError("Unix domain sockets are not supported on this platform".to_owned())
+}
@@ -1,3 +1,5 @@
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
@@ -171,6 +173,33 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+#[test]
+fn cli_unknown_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "rocksdb", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("possible values: kvs, sled"));
+    // Nothing was marked with the unknown engine.
+    assert!(!temp_dir.path().join("config").exists());
+}
+
+// Timeouts too long for a `Duration` are refused, not a crash.
+#[test]
+fn cli_timeout_overflow() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--read-timeout", "1e20", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .assert()
+        .code(1)
+        .stderr(contains("--read-timeout"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -212,18 +241,19 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, addr: &str, mode: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--addr", addr, "--mode", mode])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap(); // release the engine lock before the server restarts
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -298,13 +328,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(&["--engine", engine, "--addr", addr, "--mode", mode])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().unwrap();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -328,10 +359,688 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "127.0.0.1:4004", "threaded");
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "127.0.0.1:4005", "threaded");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_access_server_unix_socket() {
+    // The socket path is relative to the temporary directory both processes run in.
+    cli_access_server("kvs", "unix:kvs.sock", "threaded");
+}
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server("kvs", "127.0.0.1:4007", "async");
+}
+
+#[test]
+fn cli_access_async_server_sled_engine() {
+    cli_access_server("sled", "127.0.0.1:4008", "async");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_access_async_server_unix_socket() {
+    cli_access_server("kvs", "unix:kvs.sock", "async");
+}
+
+#[test]
+fn cli_access_evented_server_kvs_engine() {
+    cli_access_server("kvs", "127.0.0.1:4010", "evented");
+}
+
+#[test]
+fn cli_access_evented_server_sled_engine() {
+    cli_access_server("sled", "127.0.0.1:4011", "evented");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_access_evented_server_unix_socket() {
+    cli_access_server("kvs", "unix:kvs.sock", "evented");
+}
+
+// `kvs-server` must not delete a file that is not a socket to listen on its path.
+#[test]
+#[cfg(unix)]
+fn cli_unix_socket_over_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.sock"), "data").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", "unix:kvs.sock"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert_eq!(
+        fs::read_to_string(temp_dir.path().join("kvs.sock")).unwrap(),
+        "data"
+    );
+}
+
+// `kvs-server` should stop gracefully on SIGTERM, keeping the data written so far.
+#[cfg(unix)]
+fn cli_graceful_shutdown(addr: &str, mode: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--mode", mode])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::new("kill")
+        .args(&["-TERM", &child.id().to_string()])
+        .assert()
+        .success();
+    assert!(child.wait().unwrap().success());
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", addr, "--mode", mode])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_graceful_shutdown_threaded() {
+    cli_graceful_shutdown("127.0.0.1:4006", "threaded");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_graceful_shutdown_async() {
+    cli_graceful_shutdown("127.0.0.1:4009", "async");
+}
+
+#[test]
+#[cfg(unix)]
+fn cli_graceful_shutdown_evented() {
+    cli_graceful_shutdown("127.0.0.1:4012", "evented");
+}
+
+/// An ACL granting every right to `admin`, which primaries and cluster nodes need.
+const ACL: &str = r#"{
+    "users": {
+        "admin": {
+            "token": "admin-token",
+            "grants": [{ "prefix": "", "access": "admin" }]
+        }
+    }
+}"#;
+/// The flags to authenticate as `admin`.
+const ADMIN: [&str; 4] = ["--user", "admin", "--token", "admin-token"];
+
+/// Poll `addr` with `kvs-client get key` until it prints `expected`.
+fn wait_for_value(addr: &str, key: &str, expected: &str) {
+    for _ in 0..50 {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", addr])
+            .output()
+            .unwrap();
+        if String::from_utf8_lossy(&output.stdout) == expected {
+            return;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("{} never got {} = {:?}", addr, key, expected);
+}
+
+#[test]
+fn cli_replication() {
+    let primary_addr = "127.0.0.1:4013";
+    let replica_addr = "127.0.0.1:4014";
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    fs::write(primary_dir.path().join("acl.json"), ACL).unwrap();
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", primary_addr, "--acl", "acl.json"])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", primary_addr])
+        .args(&ADMIN)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key2", "value2", "--addr", primary_addr])
+        .args(&ADMIN)
+        .assert()
+        .success();
+
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--addr", replica_addr, "--replica-of", primary_addr])
+        .args(&ADMIN)
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+
+    // The replica gets a copy of the existing data, then the new writes.
+    wait_for_value(replica_addr, "key1", "value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key3", "value3", "--addr", primary_addr])
+        .args(&ADMIN)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key2", "--addr", primary_addr])
+        .args(&ADMIN)
+        .assert()
+        .success();
+    wait_for_value(replica_addr, "key3", "value3\n");
+    wait_for_value(replica_addr, "key2", "Key not found\n");
+
+    // Writes to the replica are redirected to the primary.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key4", "value4", "--addr", replica_addr])
+        .assert()
+        .failure()
+        .stderr(contains(primary_addr));
+
+    replica.kill().expect("replica exited before killed");
+    primary.kill().expect("primary exited before killed");
+    replica.wait().unwrap();
+    primary.wait().unwrap();
+}
+
+/// A cluster of `kvs-server` processes, each in its own directory, which nodes
+/// can be killed and restarted.
+struct Cluster {
+    addrs: Vec<&'static str>,
+    dirs: Vec<TempDir>,
+    nodes: Vec<Option<std::process::Child>>,
+}
+
+impl Cluster {
+    fn start(addrs: Vec<&'static str>) -> Cluster {
+        let mut cluster = Cluster {
+            dirs: addrs.iter().map(|_| TempDir::new().unwrap()).collect(),
+            nodes: addrs.iter().map(|_| None).collect(),
+            addrs,
+        };
+        for node in 0..cluster.addrs.len() {
+            cluster.restart(node);
+        }
+        cluster
+    }
+
+    fn restart(&mut self, node: usize) {
+        fs::write(self.dirs[node].path().join("acl.json"), ACL).unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--addr", self.addrs[node], "--acl", "acl.json"])
+            .args(&["--cluster", &self.addrs.join(",")])
+            .args(&ADMIN)
+            .current_dir(&self.dirs[node])
+            .spawn()
+            .unwrap();
+        self.nodes[node] = Some(child);
+    }
+
+    fn kill(&mut self, node: usize) {
+        let mut child = self.nodes[node].take().unwrap();
+        child.kill().expect("node exited before killed");
+        child.wait().unwrap();
+    }
+
+    /// Wait for a running node to lead the cluster, i.e. to serve reads itself.
+    fn leader(&self) -> usize {
+        for _ in 0..100 {
+            for node in (0..self.addrs.len()).filter(|node| self.nodes[*node].is_some()) {
+                let status = Command::cargo_bin("kvs-client")
+                    .unwrap()
+                    .args(&["get", "probe", "--addr", self.addrs[node]])
+                    .args(&ADMIN)
+                    .output()
+                    .unwrap()
+                    .status;
+                if status.success() {
+                    return node;
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("no leader elected");
+    }
+
+    fn set(&self, node: usize, key: &str, value: &str) {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", key, value, "--addr", self.addrs[node]])
+            .args(&ADMIN)
+            .arg("--follow-redirects")
+            .assert()
+            .success();
+    }
+
+    fn get(&self, node: usize, key: &str) -> String {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", key, "--addr", self.addrs[node]])
+            .args(&ADMIN)
+            .arg("--follow-redirects")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for child in self.nodes.iter_mut().flatten() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[test]
+fn cli_cluster() {
+    let mut cluster = Cluster::start(vec!["127.0.0.1:4015", "127.0.0.1:4016", "127.0.0.1:4017"]);
+    let leader = cluster.leader();
+    let follower = (leader + 1) % 3;
+    // A replica may follow any node, not only the leader.
+    let replica_dir = TempDir::new().unwrap();
+    let replica_addr = "127.0.0.1:4027";
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--addr",
+            replica_addr,
+            "--replica-of",
+            cluster.addrs[follower],
+        ])
+        .args(&ADMIN)
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Followers redirect clients to the leader.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", cluster.addrs[follower]])
+        .args(&ADMIN)
+        .assert()
+        .failure()
+        .stderr(contains(cluster.addrs[leader]));
+    cluster.set(follower, "key1", "value1");
+    assert_eq!(cluster.get(follower, "key1"), "value1\n");
+    thread::sleep(Duration::from_millis(500));
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", replica_addr])
+        .output()
+        .unwrap();
+    replica.kill().expect("replica exited before killed");
+    assert_eq!(output.stdout, b"value1\n");
+
+    // Another node takes over when the leader dies, with the committed writes.
+    cluster.kill(leader);
+    let new_leader = cluster.leader();
+    assert_ne!(new_leader, leader);
+    assert_eq!(cluster.get(new_leader, "key1"), "value1\n");
+    cluster.set(new_leader, "key2", "value2");
+
+    // The old leader catches up once restarted, and the cluster survives losing another node.
+    cluster.restart(leader);
+    thread::sleep(Duration::from_secs(2));
+    cluster.kill(new_leader);
+    let last_leader = cluster.leader();
+    assert_ne!(last_leader, new_leader);
+    assert_eq!(cluster.get(last_leader, "key1"), "value1\n");
+    assert_eq!(cluster.get(last_leader, "key2"), "value2\n");
+    cluster.set(leader, "key3", "value3");
+    assert_eq!(cluster.get(leader, "key3"), "value3\n");
+}
+
+#[test]
+fn cli_proxy() {
+    let backend_addrs = ["127.0.0.1:4018", "127.0.0.1:4019"];
+    let proxy_addr = "127.0.0.1:4020";
+    let dirs: Vec<_> = backend_addrs
+        .iter()
+        .map(|_| TempDir::new().unwrap())
+        .collect();
+    // The proxy keeps connections open, which must not hold the threads of the backends.
+    let mut backends: Vec<_> = backend_addrs
+        .iter()
+        .zip(&dirs)
+        .map(|(addr, dir)| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(&["--addr", addr, "--mode", "evented"])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+    let mut proxy = Command::cargo_bin("kvs-proxy")
+        .unwrap()
+        .args(&["--addr", proxy_addr, "--backends", &backend_addrs.join(",")])
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // Every key set through the proxy lands on exactly one backend.
+    for i in 0..10 {
+        let (key, value) = (format!("key{}", i), format!("value{}", i));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &key, &value, "--addr", proxy_addr])
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", &key, "--addr", proxy_addr])
+            .assert()
+            .success()
+            .stdout(format!("{}\n", value));
+        let held = backend_addrs
+            .iter()
+            .filter(|addr| {
+                let output = Command::cargo_bin("kvs-client")
+                    .unwrap()
+                    .args(&["get", &key, "--addr", addr])
+                    .output()
+                    .unwrap();
+                output.stdout == format!("{}\n", value).as_bytes()
+            })
+            .count();
+        assert_eq!(held, 1);
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key0", "--addr", proxy_addr])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["rm", "key0", "--addr", proxy_addr])
+        .assert()
+        .failure()
+        .stderr(contains("Key not found"));
+
+    proxy.kill().expect("proxy exited before killed");
+    proxy.wait().unwrap();
+    for backend in &mut backends {
+        backend.kill().expect("backend exited before killed");
+        backend.wait().unwrap();
+    }
+}
+
+#[test]
+fn cli_proxy_zero_options() {
+    for flag in &["--pool-size", "--failure-limit"] {
+        Command::cargo_bin("kvs-proxy")
+            .unwrap()
+            .args(&["--addr", "127.0.0.1:4020", "--backends", "127.0.0.1:4021"])
+            .args(&[flag, "0"])
+            .assert()
+            .code(1)
+            .stderr(contains(*flag));
+    }
+}
+
+#[test]
+fn cli_config_file() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(
+        temp_dir.path().join("kvs.toml"),
+        r#"
+addr = "127.0.0.1:4021"
+data_dir = "data"
+engine = "sled"
+mode = "evented"
+
+[pool]
+kind = "shared-queue"
+size = 2
+
+[storage]
+durability = "sync"
+
+[log]
+level = "debug"
+file = "server.log"
+"#,
+    )
+    .unwrap();
+
+    // The flags win over the environment, which wins over the file.
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "kvs.toml", "--engine", "kvs"])
+        .env("KVS_ADDR", "127.0.0.1:4022")
+        .env("KVS_ENGINE", "sled")
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4022"])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+
+    let marker = fs::read_to_string(temp_dir.path().join("data").join("config")).unwrap();
+    assert!(marker.contains("kvs"));
+    let log = fs::read_to_string(temp_dir.path().join("server.log")).unwrap();
+    assert!(log.contains("127.0.0.1:4022"));
+    assert!(log.contains("Evented"));
+    assert!(log.contains("SharedQueue of 2 threads"));
+
+    fs::write(
+        temp_dir.path().join("typo.toml"),
+        "adr = \"127.0.0.1:4023\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--config", "typo.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("unknown field `adr`"));
+}
+
+#[test]
+fn cli_data_dir_lock() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "data", "--addr", "127.0.0.1:4023"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "data", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("DirectoryLocked"));
+
+    // The first server keeps serving.
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", "127.0.0.1:4023"])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "data", "--addr", "127.0.0.1:4024"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", "127.0.0.1:4024"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    child.kill().expect("server exited before killed");
+}
+
+#[test]
+fn cli_migrate_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4025";
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "kvs", "--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for i in 0..3 {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", &format!("key{}", i), &format!("value{}", i)])
+            .args(&["--addr", addr])
+            .assert()
+            .success();
+    }
+
+    // The running server holds the directory.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--data-dir",
+            "kvs",
+            "migrate",
+            "--to",
+            "sled",
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    child.kill().expect("server exited before killed");
+    child.wait().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--data-dir",
+            "kvs",
+            "migrate",
+            "--to",
+            "sled",
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("3 keys"));
+    assert!(!temp_dir.path().join(".sled.migrating").exists());
+    // A failed migration leaves nothing behind.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--data-dir",
+            "kvs",
+            "migrate",
+            "--to",
+            "rocksdb",
+            "--engine",
+            "rocksdb",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    assert!(!temp_dir.path().join("rocksdb").exists());
+    assert!(!temp_dir.path().join(".rocksdb.migrating").exists());
+    // The target must be fresh.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&[
+            "--data-dir",
+            "kvs",
+            "migrate",
+            "--to",
+            "sled",
+            "--engine",
+            "sled",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+
+    // The new directory is marked with its engine.
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "sled", "--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--data-dir", "sled", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["info", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("engine:sled"))
+        .stdout(contains("keys:3"));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key2", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value2\n");
+    child.kill().expect("server exited before killed");
 }
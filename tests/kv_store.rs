@@ -1,4 +1,4 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{copy_engine, Checksum, KvStore, KvsEngine, KvsError, Result, SledKvStore};
 use std::sync::{Arc, Barrier};
 use std::thread;
 use tempfile::TempDir;
@@ -81,6 +81,50 @@ fn remove_key() -> Result<()> {
     Ok(())
 }
 
+// Batches should run in order, and report missing keys per key
+fn check_batches(store: impl KvsEngine) -> Result<()> {
+    store.set_many(vec![
+        ("key1".to_owned(), "value1".to_owned()),
+        ("key2".to_owned(), "value2".to_owned()),
+        ("key1".to_owned(), "value3".to_owned()),
+    ])?;
+    let keys = vec!["key1".to_owned(), "key3".to_owned(), "key2".to_owned()];
+    assert_eq!(
+        store.get_many(keys.clone())?,
+        vec![Some("value3".to_owned()), None, Some("value2".to_owned())]
+    );
+
+    let removed = store.remove_many(vec![
+        "key2".to_owned(),
+        "key3".to_owned(),
+        "key2".to_owned(),
+    ])?;
+    assert_eq!(removed, vec![true, false, false]);
+    assert_eq!(
+        store.get_many(keys)?,
+        vec![Some("value3".to_owned()), None, None]
+    );
+    Ok(())
+}
+
+#[test]
+fn batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batches(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    let keys = vec!["key1".to_owned(), "key2".to_owned()];
+    assert_eq!(store.get_many(keys)?, vec![Some("value3".to_owned()), None]);
+    Ok(())
+}
+
+#[test]
+fn sled_batches() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batches(SledKvStore::open(temp_dir.path())?)
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
@@ -209,3 +253,47 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// A directory should be opened by one store at a time
+#[test]
+fn lock_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    drop(store);
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryLocked(path)) => assert_eq!(path, temp_dir.path()),
+        other => panic!("expected a locked directory, got {:?}", other.err()),
+    }
+
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    Ok(())
+}
+
+// Copying an engine into another should keep every key/value
+#[test]
+fn copy_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::create_dir(temp_dir.path().join("kvs"))?;
+    let source = KvStore::open(temp_dir.path().join("kvs"))?;
+    for i in 0..250 {
+        source.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    source.remove("key7".to_owned())?;
+
+    let target = SledKvStore::open(temp_dir.path().join("sled"))?;
+    let checksum = copy_engine(&source, &target)?;
+    assert_eq!(checksum.keys, 249);
+    assert_eq!(Checksum::of(&target)?, Checksum::of(&source)?);
+    assert_eq!(target.get("key8".to_owned())?, Some("value8".to_owned()));
+    assert_eq!(target.get("key7".to_owned())?, None);
+
+    // The target must be empty.
+    assert!(copy_engine(&source, &target).is_err());
+
+    target.set("key8".to_owned(), "other".to_owned())?;
+    assert_ne!(Checksum::of(&target)?, Checksum::of(&source)?);
+    Ok(())
+}